@@ -10,6 +10,8 @@ pub enum Declaration {
   Use(UseDeclaration),
   Mod(ModDeclaration),
   Fn(FnDeclaration),
+  Struct(StructDeclaration),
+  Impl(ImplDeclaration),
 }
 
 #[derive(Debug, PartialEq)]
@@ -42,6 +44,18 @@ pub struct FnDeclaration {
   pub body: Block,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct StructDeclaration {
+  pub name: Spanned<Token>,
+  pub fields: Vec<Spanned<Token>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ImplDeclaration {
+  pub name: Spanned<Token>,
+  pub fn_declarations: Vec<FnDeclaration>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Block {
   pub statements: Vec<Statement>,
@@ -55,6 +69,7 @@ pub enum Statement {
 
 #[derive(Debug, PartialEq)]
 pub struct BindStatement {
+  pub keyword: Spanned<Token>,
   pub is_mut: bool,
   pub pattern: Pattern,
   pub expression: Expression,
@@ -70,13 +85,52 @@ pub struct ExpressionStatement {
 pub enum Expression {
   Literal(Spanned<Token>),
   Path(Path),
+  List(ListExpression),
+  Map(MapExpression),
+  Struct(StructExpression),
   Access(AccessExpression),
   Call(CallExpression),
+  Index(IndexExpression),
   Unary(UnaryExpression),
   Binary(BinaryExpression),
   Assign(AssignExpression),
+  IndexAssign(IndexAssignExpression),
+  AccessAssign(AccessAssignExpression),
   If(IfExpression),
   While(WhileExpression),
+  Try(TryExpression),
+  Throw(ThrowExpression),
+  Fn(FnExpression),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ListExpression {
+  pub bracket: Spanned<Token>,
+  pub elements: Vec<Expression>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MapExpression {
+  pub bracket: Spanned<Token>,
+  pub entries: Vec<MapEntry>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MapEntry {
+  pub key: Expression,
+  pub value: Expression,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StructExpression {
+  pub name: Spanned<Token>,
+  pub fields: Vec<FieldInitializer>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FieldInitializer {
+  pub name: Spanned<Token>,
+  pub value: Expression,
 }
 
 #[derive(Debug, PartialEq)]
@@ -91,6 +145,12 @@ pub struct CallExpression {
   pub arguments: Vec<Expression>,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct IndexExpression {
+  pub receiver: Box<Expression>,
+  pub index: Box<Expression>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct UnaryExpression {
   pub operator: Spanned<Token>,
@@ -110,8 +170,23 @@ pub struct AssignExpression {
   pub operand: Box<Expression>,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct IndexAssignExpression {
+  pub receiver: Box<Expression>,
+  pub index: Box<Expression>,
+  pub operand: Box<Expression>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AccessAssignExpression {
+  pub receiver: Box<Expression>,
+  pub field: Spanned<Token>,
+  pub operand: Box<Expression>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct IfExpression {
+  pub keyword: Spanned<Token>,
   pub condition: Box<Expression>,
   pub body: Block,
   pub else_body: Option<ElseBody>,
@@ -125,10 +200,33 @@ pub enum ElseBody {
 
 #[derive(Debug, PartialEq)]
 pub struct WhileExpression {
+  pub keyword: Spanned<Token>,
   pub condition: Box<Expression>,
   pub body: Block,
 }
 
+// The value thrown within the body is bound to the name for the catch body.
+#[derive(Debug, PartialEq)]
+pub struct TryExpression {
+  pub keyword: Spanned<Token>,
+  pub body: Block,
+  pub name: Spanned<Token>,
+  pub catch_body: Block,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ThrowExpression {
+  pub keyword: Spanned<Token>,
+  pub operand: Box<Expression>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FnExpression {
+  pub keyword: Spanned<Token>,
+  pub parameters: Vec<Spanned<Token>>,
+  pub body: Block,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Path {
   pub components: Vec<Spanned<Token>>,
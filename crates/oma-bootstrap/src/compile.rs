@@ -1,16 +1,23 @@
-use std::{collections::HashMap, fs, io};
+use std::{
+  collections::{HashMap, HashSet},
+  fs, io,
+};
 
 use crate::{
   ast,
   ir::{
-    AccessExpression, BinaryExpression, BinaryOperator, BindStatement, Block,
-    CallExpression, Chunk, ElseBody, Executable, Expression,
-    ExpressionStatement, IfExpression, LiteralExpression, ModHeader,
-    PackageHeader, Path, Statement, UnaryExpression, UnaryOperator,
-    WhileExpression,
+    AccessAssignExpression, AccessExpression, AssignExpression,
+    BinaryExpression, BinaryOperator, BindStatement, Block, CallExpression,
+    Chunk, ElseBody, Executable, Expression, ExpressionStatement,
+    FieldInitializer, FnExpression, IfExpression, ImplHeader,
+    IndexAssignExpression, IndexExpression, InvokeExpression, ListExpression,
+    LiteralExpression, MapEntry, MapExpression, ModHeader, PackageHeader, Path,
+    Statement, StructExpression, StructHeader, ThrowExpression, TryExpression,
+    UnaryExpression, UnaryOperator, WhileExpression,
   },
+  lex::string_literal,
   parse::{ParseError, Parser},
-  span::Spanned,
+  span::{Span, Spanned},
   token::Token,
 };
 
@@ -19,11 +26,31 @@ pub enum CompileError {
   Io(io::Error),
   FileNotFound,
   Parse(Vec<Spanned<ParseError>>),
+  UnknownStruct(Span),
+  UnknownField(Span),
+  UnknownMethod(Span),
+  MissingField(Span),
+  MissingSelf(Span),
+  UnknownLocal(Span),
+  ImmutableLocal(Span),
 }
 
 pub struct Compiler {
   identifiers: HashMap<String, usize>,
   chunks: Vec<Chunk>,
+  structs: HashMap<usize, Vec<usize>>,
+  methods: HashMap<usize, Vec<usize>>,
+  scopes: Vec<Scope>,
+}
+
+// The identifiers bound within a function, and the identifiers it captures
+// from the functions enclosing it. The struct of a local is known if it is
+// immutable and bound directly to a struct expression.
+struct Scope {
+  locals: Vec<usize>,
+  mutables: HashSet<usize>,
+  captures: Vec<usize>,
+  types: HashMap<usize, usize>,
 }
 
 impl Compiler {
@@ -31,6 +58,9 @@ impl Compiler {
     Compiler {
       identifiers: HashMap::new(),
       chunks: Vec::new(),
+      structs: HashMap::new(),
+      methods: HashMap::new(),
+      scopes: Vec::new(),
     }
   }
 
@@ -56,6 +86,10 @@ impl Compiler {
     let mut use_declarations = Vec::new();
     let mut mod_headers = HashMap::new();
     let mut fn_headers = HashMap::new();
+    let mut struct_headers = HashMap::new();
+    let mut impl_headers = HashMap::new();
+
+    self.register_types(&file.declarations)?;
 
     for declaration in file.declarations {
       match declaration {
@@ -72,6 +106,27 @@ impl Compiler {
             self.add_identifier(fn_declaration.name.span().to_string());
           fn_headers.insert(name, self.fn_header(fn_declaration)?);
         }
+        ast::Declaration::Struct(struct_declaration) => {
+          let name =
+            self.add_identifier(struct_declaration.name.span().to_string());
+          let fields = self.structs[&name].clone();
+          struct_headers.insert(name, StructHeader { fields });
+        }
+        ast::Declaration::Impl(impl_declaration) => {
+          let name =
+            self.add_identifier(impl_declaration.name.span().to_string());
+          let impl_header =
+            impl_headers.entry(name).or_insert_with(|| ImplHeader {
+              fn_headers: HashMap::new(),
+            });
+          for fn_declaration in impl_declaration.fn_declarations {
+            let method =
+              self.add_identifier(fn_declaration.name.span().to_string());
+            impl_header
+              .fn_headers
+              .insert(method, self.method_header(name, fn_declaration)?);
+          }
+        }
       };
     }
 
@@ -80,6 +135,8 @@ impl Compiler {
       use_declarations,
       mod_headers,
       fn_headers,
+      struct_headers,
+      impl_headers,
     })
   }
 
@@ -102,6 +159,10 @@ impl Compiler {
     let mut use_declarations = Vec::new();
     let mut mod_headers = HashMap::new();
     let mut fn_headers = HashMap::new();
+    let mut struct_headers = HashMap::new();
+    let mut impl_headers = HashMap::new();
+
+    self.register_types(&body.declarations)?;
 
     for declaration in body.declarations {
       match declaration {
@@ -118,6 +179,27 @@ impl Compiler {
             self.add_identifier(fn_declaration.name.span().to_string());
           fn_headers.insert(name, self.fn_header(fn_declaration)?);
         }
+        ast::Declaration::Struct(struct_declaration) => {
+          let name =
+            self.add_identifier(struct_declaration.name.span().to_string());
+          let fields = self.structs[&name].clone();
+          struct_headers.insert(name, StructHeader { fields });
+        }
+        ast::Declaration::Impl(impl_declaration) => {
+          let name =
+            self.add_identifier(impl_declaration.name.span().to_string());
+          let impl_header =
+            impl_headers.entry(name).or_insert_with(|| ImplHeader {
+              fn_headers: HashMap::new(),
+            });
+          for fn_declaration in impl_declaration.fn_declarations {
+            let method =
+              self.add_identifier(fn_declaration.name.span().to_string());
+            impl_header
+              .fn_headers
+              .insert(method, self.method_header(name, fn_declaration)?);
+          }
+        }
       };
     }
 
@@ -125,22 +207,106 @@ impl Compiler {
       use_declarations,
       mod_headers,
       fn_headers,
+      struct_headers,
+      impl_headers,
     })
   }
 
+  // Structs and their methods are registered before any functions are
+  // compiled, so that they can be used regardless of declaration order.
+  fn register_types(
+    &mut self,
+    declarations: &[ast::Declaration],
+  ) -> Result<(), CompileError> {
+    for declaration in declarations {
+      if let ast::Declaration::Struct(struct_declaration) = declaration {
+        let name =
+          self.add_identifier(struct_declaration.name.span().to_string());
+        let fields = struct_declaration
+          .fields
+          .iter()
+          .map(|field| self.add_identifier(field.span().to_string()))
+          .collect();
+        self.structs.insert(name, fields);
+      }
+    }
+
+    for declaration in declarations {
+      if let ast::Declaration::Impl(impl_declaration) = declaration {
+        let name =
+          self.add_identifier(impl_declaration.name.span().to_string());
+        if !self.structs.contains_key(&name) {
+          return Err(CompileError::UnknownStruct(
+            impl_declaration.name.span().clone(),
+          ));
+        }
+        for fn_declaration in impl_declaration.fn_declarations.iter() {
+          let method =
+            self.add_identifier(fn_declaration.name.span().to_string());
+          self
+            .methods
+            .entry(name)
+            .or_insert_with(Vec::new)
+            .push(method);
+        }
+      }
+    }
+
+    Ok(())
+  }
+
   fn fn_header(
     &mut self,
     fn_declaration: ast::FnDeclaration,
   ) -> Result<usize, CompileError> {
-    let parameters = fn_declaration
+    self.function(fn_declaration, HashMap::new())
+  }
+
+  // The receiver of a method is bound to its first parameter, which must be
+  // named `self`.
+  fn method_header(
+    &mut self,
+    structure: usize,
+    fn_declaration: ast::FnDeclaration,
+  ) -> Result<usize, CompileError> {
+    match fn_declaration.parameters.first() {
+      Some(parameter) if parameter.span().as_str() == "self" => {}
+      _ => {
+        return Err(CompileError::MissingSelf(
+          fn_declaration.name.span().clone(),
+        ))
+      }
+    }
+
+    let mut types = HashMap::new();
+    types.insert(self.add_identifier("self".to_string()), structure);
+    self.function(fn_declaration, types)
+  }
+
+  fn function(
+    &mut self,
+    fn_declaration: ast::FnDeclaration,
+    types: HashMap<usize, usize>,
+  ) -> Result<usize, CompileError> {
+    let parameters: Vec<usize> = fn_declaration
       .parameters
       .iter()
       .map(|token| self.add_identifier(token.span().to_string()))
       .collect();
 
-    let body = self.block(fn_declaration.body)?;
+    self.scopes.push(Scope {
+      locals: parameters.clone(),
+      mutables: HashSet::new(),
+      captures: Vec::new(),
+      types,
+    });
+    let body = self.block(fn_declaration.body);
+    self.scopes.pop();
 
-    let chunk = self.add_chunk(Chunk { parameters, body });
+    let chunk = self.add_chunk(Chunk {
+      parameters,
+      body: body?,
+    });
 
     Ok(chunk)
   }
@@ -183,10 +349,27 @@ impl Compiler {
     match bind_statement.pattern {
       ast::Pattern::Literal(token) => match token.base() {
         Token::Identifier => {
-          let name = self.add_identifier(token.span().to_string());
+          let structure = self.struct_type(&bind_statement.expression);
           let expression = expression_or_expressions(
             self.expression(bind_statement.expression)?,
           );
+          let name = self.add_identifier(token.span().to_string());
+          if let Some(scope) = self.scopes.last_mut() {
+            scope.locals.push(name);
+            if bind_statement.is_mut {
+              scope.mutables.insert(name);
+            } else {
+              scope.mutables.remove(&name);
+            }
+            match structure {
+              Some(structure) if !bind_statement.is_mut => {
+                scope.types.insert(name, structure);
+              }
+              _ => {
+                scope.types.remove(&name);
+              }
+            }
+          }
           Ok(vec![Statement::Bind(BindStatement {
             is_mut: bind_statement.is_mut,
             name,
@@ -225,12 +408,31 @@ impl Compiler {
       ast::Expression::Path(path) => self
         .path_expression(path)
         .map(|path| vec![Expression::Path(path)]),
+      ast::Expression::List(list_expression) => self
+        .list_expression(list_expression)
+        .map(|list_expression| vec![Expression::List(list_expression)]),
+      ast::Expression::Map(map_expression) => self
+        .map_expression(map_expression)
+        .map(|map_expression| vec![Expression::Map(map_expression)]),
+      ast::Expression::Struct(struct_expression) => self
+        .struct_expression(struct_expression)
+        .map(|struct_expression| vec![Expression::Struct(struct_expression)]),
       ast::Expression::Access(access_expression) => self
         .access_expression(access_expression)
         .map(|access_expression| vec![Expression::Access(access_expression)]),
+      ast::Expression::Call(call_expression)
+        if matches!(*call_expression.receiver, ast::Expression::Access(_)) =>
+      {
+        self
+          .invoke_expression(call_expression)
+          .map(|invoke_expression| vec![Expression::Invoke(invoke_expression)])
+      }
       ast::Expression::Call(call_expression) => self
         .call_expression(call_expression)
         .map(|call_expression| vec![Expression::Call(call_expression)]),
+      ast::Expression::Index(index_expression) => self
+        .index_expression(index_expression)
+        .map(|index_expression| vec![Expression::Index(index_expression)]),
       ast::Expression::Unary(unary_expression) => self
         .unary_expression(unary_expression)
         .map(|unary_expression| vec![Expression::Unary(unary_expression)]),
@@ -239,13 +441,32 @@ impl Compiler {
         .map(|binary_expression| vec![Expression::Binary(binary_expression)]),
       ast::Expression::Assign(assign_expression) => self
         .assign_expression(assign_expression)
-        .map(|assign_expression| vec![]),
+        .map(|assign_expression| vec![Expression::Assign(assign_expression)]),
+      ast::Expression::IndexAssign(index_assign_expression) => self
+        .index_assign_expression(index_assign_expression)
+        .map(|index_assign_expression| {
+          vec![Expression::IndexAssign(index_assign_expression)]
+        }),
+      ast::Expression::AccessAssign(access_assign_expression) => self
+        .access_assign_expression(access_assign_expression)
+        .map(|access_assign_expression| {
+          vec![Expression::AccessAssign(access_assign_expression)]
+        }),
       ast::Expression::If(if_expression) => self
         .if_expression(if_expression)
         .map(|if_expression| vec![Expression::If(if_expression)]),
       ast::Expression::While(while_expression) => self
         .while_expression(while_expression)
         .map(|while_expression| vec![Expression::While(while_expression)]),
+      ast::Expression::Try(try_expression) => self
+        .try_expression(try_expression)
+        .map(|try_expression| vec![Expression::Try(try_expression)]),
+      ast::Expression::Throw(throw_expression) => self
+        .throw_expression(throw_expression)
+        .map(|throw_expression| vec![Expression::Throw(throw_expression)]),
+      ast::Expression::Fn(fn_expression) => self
+        .fn_expression(fn_expression)
+        .map(|fn_expression| vec![Expression::Fn(fn_expression)]),
     }
   }
 
@@ -260,11 +481,18 @@ impl Compiler {
       Token::Float => Ok(LiteralExpression::Float(
         token.span().as_str().parse().unwrap(),
       )),
+      Token::String => Ok(LiteralExpression::String(string_literal(
+        token.span().as_str(),
+      ))),
       Token::True => Ok(LiteralExpression::Bool(true)),
       Token::False => Ok(LiteralExpression::Bool(false)),
-      Token::Identifier => Ok(LiteralExpression::Identifier(
-        self.add_identifier(token.span().to_string()),
-      )),
+      Token::Identifier => {
+        let identifier = self.add_identifier(token.span().to_string());
+        if let Some(level) = self.scopes.len().checked_sub(1) {
+          self.capture(level, identifier);
+        }
+        Ok(LiteralExpression::Identifier(identifier))
+      }
       _ => unreachable!(),
     }
   }
@@ -279,20 +507,144 @@ impl Compiler {
     })
   }
 
+  fn list_expression(
+    &mut self,
+    list_expression: ast::ListExpression,
+  ) -> Result<ListExpression, CompileError> {
+    let elements = list_expression
+      .elements
+      .into_iter()
+      .map(|expression| self.expression(expression))
+      .collect::<Result<Vec<Vec<Expression>>, CompileError>>()?
+      .into_iter()
+      .map(expression_or_expressions)
+      .collect();
+
+    Ok(ListExpression { elements })
+  }
+
+  fn map_expression(
+    &mut self,
+    map_expression: ast::MapExpression,
+  ) -> Result<MapExpression, CompileError> {
+    let entries = map_expression
+      .entries
+      .into_iter()
+      .map(|entry| {
+        let key = expression_or_expressions(self.expression(entry.key)?);
+        let value = expression_or_expressions(self.expression(entry.value)?);
+        Ok(MapEntry { key, value })
+      })
+      .collect::<Result<Vec<MapEntry>, CompileError>>()?;
+
+    Ok(MapExpression { entries })
+  }
+
+  fn struct_expression(
+    &mut self,
+    struct_expression: ast::StructExpression,
+  ) -> Result<StructExpression, CompileError> {
+    let name = self.add_identifier(struct_expression.name.span().to_string());
+    let declared = self.structs.get(&name).cloned().ok_or_else(|| {
+      CompileError::UnknownStruct(struct_expression.name.span().clone())
+    })?;
+
+    // A field initialized twice is reported like one that is not declared.
+    let mut fields: Vec<FieldInitializer> = Vec::new();
+    for initializer in struct_expression.fields {
+      let field = self.add_identifier(initializer.name.span().to_string());
+      let is_repeated = fields.iter().any(|other| other.name == field);
+      if is_repeated || !declared.contains(&field) {
+        return Err(CompileError::UnknownField(
+          initializer.name.span().clone(),
+        ));
+      }
+      let value =
+        expression_or_expressions(self.expression(initializer.value)?);
+      fields.push(FieldInitializer { name: field, value });
+    }
+
+    if declared
+      .iter()
+      .any(|field| !fields.iter().any(|initializer| initializer.name == *field))
+    {
+      return Err(CompileError::MissingField(
+        struct_expression.name.span().clone(),
+      ));
+    }
+
+    Ok(StructExpression { name, fields })
+  }
+
   fn access_expression(
     &mut self,
     access_expression: ast::AccessExpression,
   ) -> Result<AccessExpression, CompileError> {
+    let field =
+      self.field(&access_expression.receiver, &access_expression.field)?;
     let receiver = Box::new(expression_or_expressions(
       self.expression(*access_expression.receiver)?,
     ));
 
-    Ok(AccessExpression {
+    Ok(AccessExpression { receiver, field })
+  }
+
+  fn access_assign_expression(
+    &mut self,
+    access_assign_expression: ast::AccessAssignExpression,
+  ) -> Result<AccessAssignExpression, CompileError> {
+    let field = self.field(
+      &access_assign_expression.receiver,
+      &access_assign_expression.field,
+    )?;
+    let receiver = Box::new(expression_or_expressions(
+      self.expression(*access_assign_expression.receiver)?,
+    ));
+    let operand = Box::new(expression_or_expressions(
+      self.expression(*access_assign_expression.operand)?,
+    ));
+
+    Ok(AccessAssignExpression {
       receiver,
-      field: self.add_identifier(access_expression.field.span().to_string()),
+      field,
+      operand,
     })
   }
 
+  // Resolves the field being accessed, checking that it exists if the struct
+  // of the receiver is known.
+  fn field(
+    &mut self,
+    receiver: &ast::Expression,
+    field: &Spanned<Token>,
+  ) -> Result<usize, CompileError> {
+    let structure = self.struct_type(receiver);
+    let field_identifier = self.add_identifier(field.span().to_string());
+    match structure {
+      Some(structure)
+        if !self.structs[&structure].contains(&field_identifier) =>
+      {
+        Err(CompileError::UnknownField(field.span().clone()))
+      }
+      _ => Ok(field_identifier),
+    }
+  }
+
+  fn struct_type(&self, expression: &ast::Expression) -> Option<usize> {
+    match expression {
+      ast::Expression::Struct(struct_expression) => self
+        .identifiers
+        .get(struct_expression.name.span().as_str())
+        .copied()
+        .filter(|name| self.structs.contains_key(name)),
+      ast::Expression::Literal(token) if token.base() == &Token::Identifier => {
+        let identifier = self.identifiers.get(token.span().as_str())?;
+        self.scopes.last()?.types.get(identifier).copied()
+      }
+      _ => None,
+    }
+  }
+
   fn call_expression(
     &mut self,
     call_expression: ast::CallExpression,
@@ -316,6 +668,65 @@ impl Compiler {
     })
   }
 
+  // A call on an access expression invokes a method of the receiver, or calls
+  // the value of a field if there is no method of the same name.
+  fn invoke_expression(
+    &mut self,
+    call_expression: ast::CallExpression,
+  ) -> Result<InvokeExpression, CompileError> {
+    let access_expression = match *call_expression.receiver {
+      ast::Expression::Access(access_expression) => access_expression,
+      _ => unreachable!(),
+    };
+
+    let structure = self.struct_type(&access_expression.receiver);
+    let method =
+      self.add_identifier(access_expression.field.span().to_string());
+    if let Some(structure) = structure {
+      let methods = self.methods.get(&structure);
+      if !self.structs[&structure].contains(&method)
+        && !methods.map_or(false, |methods| methods.contains(&method))
+      {
+        return Err(CompileError::UnknownMethod(
+          access_expression.field.span().clone(),
+        ));
+      }
+    }
+
+    let receiver = Box::new(expression_or_expressions(
+      self.expression(*access_expression.receiver)?,
+    ));
+
+    let arguments = call_expression
+      .arguments
+      .into_iter()
+      .map(|expression| self.expression(expression))
+      .collect::<Result<Vec<Vec<Expression>>, CompileError>>()?
+      .into_iter()
+      .map(expression_or_expressions)
+      .collect();
+
+    Ok(InvokeExpression {
+      receiver,
+      method,
+      arguments,
+    })
+  }
+
+  fn index_expression(
+    &mut self,
+    index_expression: ast::IndexExpression,
+  ) -> Result<IndexExpression, CompileError> {
+    let receiver = Box::new(expression_or_expressions(
+      self.expression(*index_expression.receiver)?,
+    ));
+    let index = Box::new(expression_or_expressions(
+      self.expression(*index_expression.index)?,
+    ));
+
+    Ok(IndexExpression { receiver, index })
+  }
+
   fn unary_expression(
     &mut self,
     unary_expression: ast::UnaryExpression,
@@ -323,6 +734,7 @@ impl Compiler {
     let operator = match unary_expression.operator.base() {
       Token::Dash => UnaryOperator::Negate,
       Token::Bang => UnaryOperator::Not,
+      Token::Tilde => UnaryOperator::BitNot,
       _ => unreachable!(),
     };
 
@@ -349,10 +761,17 @@ impl Compiler {
       Token::Less => BinaryOperator::Less,
       Token::LessEqual => BinaryOperator::LessEqual,
       Token::EqualEqual => BinaryOperator::Equal,
+      Token::BangEqual => BinaryOperator::NotEqual,
       Token::Plus => BinaryOperator::Add,
       Token::Dash => BinaryOperator::Subtract,
       Token::Star => BinaryOperator::Multiply,
       Token::Slash => BinaryOperator::Divide,
+      Token::Percent => BinaryOperator::Remainder,
+      Token::Amp => BinaryOperator::BitAnd,
+      Token::Pipe => BinaryOperator::BitOr,
+      Token::Caret => BinaryOperator::BitXor,
+      Token::LessLess => BinaryOperator::ShiftLeft,
+      Token::GreaterGreater => BinaryOperator::ShiftRight,
       _ => unreachable!(),
     };
 
@@ -367,11 +786,57 @@ impl Compiler {
     })
   }
 
+  // Only locals declared `mut` can be assigned to, including those captured
+  // from an enclosing function.
   fn assign_expression(
     &mut self,
-    _assign_expression: ast::AssignExpression,
-  ) -> Result<Expression, CompileError> {
-    unimplemented!();
+    assign_expression: ast::AssignExpression,
+  ) -> Result<AssignExpression, CompileError> {
+    let token = match assign_expression.pattern {
+      ast::Pattern::Literal(token) if token.base() == &Token::Identifier => {
+        token
+      }
+      _ => unreachable!(),
+    };
+
+    let name = self.add_identifier(token.span().to_string());
+    let scope = self
+      .scopes
+      .iter()
+      .rev()
+      .find(|scope| scope.locals.contains(&name))
+      .ok_or_else(|| CompileError::UnknownLocal(token.span().clone()))?;
+    if !scope.mutables.contains(&name) {
+      return Err(CompileError::ImmutableLocal(token.span().clone()));
+    }
+    self.capture(self.scopes.len() - 1, name);
+
+    let operand = Box::new(expression_or_expressions(
+      self.expression(*assign_expression.operand)?,
+    ));
+
+    Ok(AssignExpression { name, operand })
+  }
+
+  fn index_assign_expression(
+    &mut self,
+    index_assign_expression: ast::IndexAssignExpression,
+  ) -> Result<IndexAssignExpression, CompileError> {
+    let receiver = Box::new(expression_or_expressions(
+      self.expression(*index_assign_expression.receiver)?,
+    ));
+    let index = Box::new(expression_or_expressions(
+      self.expression(*index_assign_expression.index)?,
+    ));
+    let operand = Box::new(expression_or_expressions(
+      self.expression(*index_assign_expression.operand)?,
+    ));
+
+    Ok(IndexAssignExpression {
+      receiver,
+      index,
+      operand,
+    })
   }
 
   fn if_expression(
@@ -419,6 +884,86 @@ impl Compiler {
     Ok(WhileExpression { condition, body })
   }
 
+  // The thrown value is bound to an immutable local for the catch body.
+  fn try_expression(
+    &mut self,
+    try_expression: ast::TryExpression,
+  ) -> Result<TryExpression, CompileError> {
+    let body = self.block(try_expression.body)?;
+    let name = self.add_identifier(try_expression.name.span().to_string());
+    if let Some(scope) = self.scopes.last_mut() {
+      scope.locals.push(name);
+      scope.mutables.remove(&name);
+      scope.types.remove(&name);
+    }
+    let catch_body = self.block(try_expression.catch_body)?;
+
+    Ok(TryExpression {
+      body,
+      name,
+      catch_body,
+    })
+  }
+
+  fn throw_expression(
+    &mut self,
+    throw_expression: ast::ThrowExpression,
+  ) -> Result<ThrowExpression, CompileError> {
+    let operand = Box::new(expression_or_expressions(
+      self.expression(*throw_expression.operand)?,
+    ));
+
+    Ok(ThrowExpression { operand })
+  }
+
+  fn fn_expression(
+    &mut self,
+    fn_expression: ast::FnExpression,
+  ) -> Result<FnExpression, CompileError> {
+    let parameters: Vec<usize> = fn_expression
+      .parameters
+      .iter()
+      .map(|token| self.add_identifier(token.span().to_string()))
+      .collect();
+
+    self.scopes.push(Scope {
+      locals: parameters.clone(),
+      mutables: HashSet::new(),
+      captures: Vec::new(),
+      types: HashMap::new(),
+    });
+    let body = self.block(fn_expression.body);
+    let scope = self.scopes.pop().unwrap();
+
+    let chunk = self.add_chunk(Chunk {
+      parameters,
+      body: body?,
+    });
+
+    Ok(FnExpression {
+      chunk,
+      captures: scope.captures,
+    })
+  }
+
+  // Returns whether the identifier is visible in the scope at the given level,
+  // capturing it through each enclosing scope that does not bind it itself.
+  fn capture(&mut self, level: usize, identifier: usize) -> bool {
+    let scope = &self.scopes[level];
+    if scope.locals.contains(&identifier)
+      || scope.captures.contains(&identifier)
+    {
+      return true;
+    }
+
+    if level == 0 || !self.capture(level - 1, identifier) {
+      return false;
+    }
+
+    self.scopes[level].captures.push(identifier);
+    true
+  }
+
   fn use_declaration(
     &mut self,
     use_declaration: ast::UseDeclaration,
@@ -496,3 +1041,165 @@ fn expression_or_expressions(mut expressions: Vec<Expression>) -> Expression {
     })
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use crate::parse::Parser;
+
+  use super::{CompileError, Compiler};
+
+  fn compile(source: &str) -> Result<(), CompileError> {
+    let file = Parser::new(source).parse().expect("failed to parse");
+    Compiler::new().package_header(file).map(|_| ())
+  }
+
+  #[test]
+  fn struct_fields() {
+    assert!(compile(
+      "
+      fn main() {
+        let point = Point { x: 1, y: 2 };
+        point.x = point.y;
+      }
+
+      struct Point { x, y }
+      ",
+    )
+    .is_ok());
+
+    assert!(matches!(
+      compile(
+        "
+        struct Point { x, y }
+        fn main() { let point = Point { x: 1, y: 2 }; point.z }
+        ",
+      ),
+      Err(CompileError::UnknownField(span)) if span.as_str() == "z"
+    ));
+
+    assert!(matches!(
+      compile(
+        "
+        struct Point { x, y }
+        fn main() { Point { x: 1, z: 2 } }
+        ",
+      ),
+      Err(CompileError::UnknownField(span)) if span.as_str() == "z"
+    ));
+
+    assert!(matches!(
+      compile(
+        "
+        struct Point { x, y }
+        fn main() { Point { x: 1, y: 2, x: 3 } }
+        ",
+      ),
+      Err(CompileError::UnknownField(span)) if span.as_str() == "x"
+    ));
+
+    assert!(matches!(
+      compile(
+        "
+        struct Point { x, y }
+        fn main() { Point { x: 1 } }
+        ",
+      ),
+      Err(CompileError::MissingField(_))
+    ));
+
+    assert!(matches!(
+      compile("fn main() { Point { x: 1 } }"),
+      Err(CompileError::UnknownStruct(_))
+    ));
+  }
+
+  #[test]
+  fn assignment() {
+    assert!(compile(
+      "
+      fn main() {
+        let mut count = 0;
+        let increment = fn() { count = count + 1; };
+        count = 2;
+      }
+      ",
+    )
+    .is_ok());
+
+    assert!(matches!(
+      compile("fn main() { let count = 0; count = 1; }"),
+      Err(CompileError::ImmutableLocal(span)) if span.as_str() == "count"
+    ));
+
+    assert!(matches!(
+      compile("fn main() { let count = 0; let f = fn() { count = 1; }; }"),
+      Err(CompileError::ImmutableLocal(span)) if span.as_str() == "count"
+    ));
+
+    assert!(matches!(
+      compile("fn main() { let mut count = 0; let count = 1; count = 2; }"),
+      Err(CompileError::ImmutableLocal(span)) if span.as_str() == "count"
+    ));
+
+    assert!(matches!(
+      compile("fn increment(count) { count = count + 1; }"),
+      Err(CompileError::ImmutableLocal(span)) if span.as_str() == "count"
+    ));
+
+    assert!(matches!(
+      compile("fn main() { count = 1; }"),
+      Err(CompileError::UnknownLocal(span)) if span.as_str() == "count"
+    ));
+  }
+
+  #[test]
+  fn methods() {
+    assert!(compile(
+      "
+      struct Counter { count }
+
+      impl Counter {
+        fn increment(self, by) {
+          self.count = self.count + by;
+        }
+      }
+
+      fn main() {
+        let counter = Counter { count: 0 };
+        counter.increment(1);
+      }
+      ",
+    )
+    .is_ok());
+
+    assert!(matches!(
+      compile(
+        "
+        struct Counter { count }
+        fn main() { let counter = Counter { count: 0 }; counter.reset() }
+        ",
+      ),
+      Err(CompileError::UnknownMethod(span)) if span.as_str() == "reset"
+    ));
+
+    assert!(matches!(
+      compile(
+        "
+        struct Counter { count }
+        impl Counter { fn get(self) { self.total } }
+        ",
+      ),
+      Err(CompileError::UnknownField(span)) if span.as_str() == "total"
+    ));
+
+    assert!(matches!(
+      compile(
+        "
+        struct Counter { count }
+        impl Counter { fn new() { Counter { count: 0 } } }
+        ",
+      ),
+      Err(CompileError::MissingSelf(span)) if span.as_str() == "new"
+    ));
+  }
+}
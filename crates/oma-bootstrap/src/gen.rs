@@ -1,33 +1,356 @@
+use std::collections::{HashMap, HashSet};
+
 use oma::{
-  executable::{Chunk, Constant},
+  executable::{
+    Capture, Chunk, Constant, Executable, Handler, Location, Struct,
+  },
   instruction::Instruction,
 };
 
 use crate::{
   ast::{
-    BinaryExpression, BindStatement, Block, ElseBody, Expression,
-    ExpressionStatement, IfExpression, Pattern, Statement, UnaryExpression,
-    WhileExpression,
+    AccessAssignExpression, AccessExpression, AssignExpression,
+    BinaryExpression, BindStatement, Block, CallExpression, Declaration,
+    ElseBody, Expression, ExpressionStatement, File, FnDeclaration,
+    FnExpression, IfExpression, IndexAssignExpression, IndexExpression,
+    ListExpression, MapExpression, Path, Pattern, Statement, StructExpression,
+    ThrowExpression, TryExpression, UnaryExpression, UseTree, WhileExpression,
   },
-  span::Spanned,
+  lex::string_literal,
+  span::{Span, Spanned},
   token::Token,
 };
 
-pub struct Generator {}
+#[derive(Debug)]
+pub enum GenerateError {
+  UnknownStruct(Span),
+  UnknownField(Span),
+  UnknownMethod(Span),
+  MissingField(Span),
+  MissingSelf(Span),
+  UnknownLocal(Span),
+  ImmutableLocal(Span),
+  UnknownNative(Span),
+}
+
+impl GenerateError {
+  pub fn span(&self) -> &Span {
+    match self {
+      GenerateError::UnknownStruct(span)
+      | GenerateError::UnknownField(span)
+      | GenerateError::UnknownMethod(span)
+      | GenerateError::MissingField(span)
+      | GenerateError::MissingSelf(span)
+      | GenerateError::UnknownLocal(span)
+      | GenerateError::ImmutableLocal(span)
+      | GenerateError::UnknownNative(span) => span,
+    }
+  }
+}
+
+pub struct Generator {
+  functions: HashMap<String, usize>,
+  structs: Vec<Struct>,
+  natives: HashSet<String>,
+  // The paths that names brought into scope by `use` declarations refer to.
+  uses: HashMap<String, String>,
+  declarations_len: usize,
+  closures: Vec<Chunk>,
+  // The scope of the function being generated, preceded by those of the
+  // functions enclosing it.
+  scopes: Vec<Scope>,
+}
+
+// The locals of a function that are in scope, in slot order, and the upvalues
+// it captures. The depth counts the values on the stack of the function's
+// frame, which are its locals along with the temporaries of the expressions
+// being evaluated.
+struct Scope {
+  locals: Vec<Local>,
+  captures: Vec<Capture>,
+  depth: usize,
+}
+
+// The struct of a local is known if it is the receiver of a method, or if it
+// is immutable and bound directly to a struct expression.
+struct Local {
+  name: String,
+  slot: usize,
+  is_mut: bool,
+  structure: Option<usize>,
+}
+
+impl Scope {
+  // Returns the innermost local of the name, which shadows any others.
+  fn local(&self, identifier: &str) -> Option<&Local> {
+    self
+      .locals
+      .iter()
+      .rev()
+      .find(|local| local.name == identifier)
+  }
+
+  // Binds a local to the slot at the top of the stack, which holds its value.
+  fn bind(
+    &mut self,
+    name: String,
+    is_mut: bool,
+    structure: Option<usize>,
+  ) -> usize {
+    let slot = self.depth;
+    self.locals.push(Local {
+      name,
+      slot,
+      is_mut,
+      structure,
+    });
+    self.depth += 1;
+    slot
+  }
+}
 
 impl Generator {
   pub fn new() -> Generator {
-    Generator {}
+    Generator {
+      functions: HashMap::new(),
+      structs: Vec::new(),
+      natives: HashSet::new(),
+      uses: HashMap::new(),
+      declarations_len: 0,
+      closures: Vec::new(),
+      scopes: Vec::new(),
+    }
+  }
+
+  // Natives are called by name, and must also be registered with the machine
+  // that executes the generated code.
+  pub fn register_native(&mut self, name: &str) {
+    self.natives.insert(name.to_string());
+  }
+
+  pub fn generate(mut self, file: File) -> Result<Executable, GenerateError> {
+    let mut fn_declarations = Vec::new();
+    let mut impl_declarations = Vec::new();
+    for declaration in file.declarations {
+      match declaration {
+        Declaration::Fn(fn_declaration) => fn_declarations.push(fn_declaration),
+        Declaration::Impl(impl_declaration) => {
+          impl_declarations.push(impl_declaration)
+        }
+        Declaration::Use(use_declaration) => {
+          for use_tree in use_declaration.trees {
+            self.use_tree("", use_tree);
+          }
+        }
+        Declaration::Struct(struct_declaration) => {
+          self.structs.push(Struct {
+            name: struct_declaration.name.span().to_string(),
+            fields: struct_declaration
+              .fields
+              .iter()
+              .map(|field| field.span().to_string())
+              .collect(),
+            methods: HashMap::new(),
+          });
+        }
+        _ => {}
+      }
+    }
+
+    // Functions are registered before any of them are generated, so that they
+    // can call each other regardless of declaration order.
+    for (index, fn_declaration) in fn_declarations.iter().enumerate() {
+      self
+        .functions
+        .insert(fn_declaration.name.span().to_string(), index);
+    }
+
+    // Methods are placed after the functions, and are registered in the method
+    // table of their struct.
+    let mut methods = Vec::new();
+    for impl_declaration in impl_declarations {
+      let name = impl_declaration.name.span().as_str();
+      let structure = match self
+        .structs
+        .iter_mut()
+        .find(|structure| structure.name == name)
+      {
+        Some(structure) => structure,
+        None => {
+          return Err(GenerateError::UnknownStruct(
+            impl_declaration.name.span().clone(),
+          ))
+        }
+      };
+      for fn_declaration in impl_declaration.fn_declarations {
+        let index = fn_declarations.len() + methods.len();
+        structure
+          .methods
+          .insert(fn_declaration.name.span().to_string(), index);
+        methods.push((name.to_string(), fn_declaration));
+      }
+    }
+    self.declarations_len = fn_declarations.len() + methods.len();
+
+    let mut executable = Executable::new();
+    for fn_declaration in fn_declarations {
+      let chunk = self.fn_declaration(fn_declaration)?;
+      executable.add_chunk(chunk);
+    }
+    for (name, fn_declaration) in methods {
+      let chunk = self.method(&name, fn_declaration)?;
+      executable.add_chunk(chunk);
+    }
+    for chunk in self.closures.drain(..) {
+      executable.add_chunk(chunk);
+    }
+    for structure in self.structs.drain(..) {
+      executable.add_struct(structure);
+    }
+    if let Some(&main) = self.functions.get("main") {
+      executable.set_entry(main);
+    }
+
+    Ok(executable)
   }
 
-  pub fn generate(mut self, block: Block) -> Chunk {
+  fn use_tree(&mut self, prefix: &str, use_tree: UseTree) {
+    match use_tree {
+      UseTree::Branch(branch) => {
+        let prefix = join_path(prefix, branch.component.span().as_str());
+        for subtree in branch.subtrees {
+          self.use_tree(&prefix, subtree);
+        }
+      }
+      UseTree::Leaf(token) => {
+        let name = token.span().to_string();
+        let path = join_path(prefix, &name);
+        self.uses.insert(name, path);
+      }
+    }
+  }
+
+  fn fn_declaration(
+    &mut self,
+    fn_declaration: FnDeclaration,
+  ) -> Result<Chunk, GenerateError> {
     let mut chunk = Chunk::new();
-    self.block(&mut chunk, block);
+    chunk.set_name(fn_declaration.name.span().to_string());
+    let parameters = fn_declaration
+      .parameters
+      .iter()
+      .map(|parameter| parameter.span().to_string())
+      .collect();
+    self.function(
+      chunk,
+      fn_declaration.name.span().to_string(),
+      None,
+      parameters,
+      fn_declaration.body,
+    )
+  }
+
+  // The receiver takes the place of the callee in slot 0, so the `self`
+  // parameter is not counted towards the arity of the method.
+  fn method(
+    &mut self,
+    name: &str,
+    fn_declaration: FnDeclaration,
+  ) -> Result<Chunk, GenerateError> {
+    let mut parameters = fn_declaration.parameters.into_iter();
+    match parameters.next() {
+      Some(parameter) if parameter.span().as_str() == "self" => {}
+      _ => {
+        return Err(GenerateError::MissingSelf(
+          fn_declaration.name.span().clone(),
+        ))
+      }
+    }
+
+    let mut chunk = Chunk::new();
+    chunk.set_name(format!(
+      "{}::{}",
+      name,
+      fn_declaration.name.span().as_str()
+    ));
+    let parameters = parameters
+      .map(|parameter| parameter.span().to_string())
+      .collect();
+    let structure = self
+      .structs
+      .iter()
+      .position(|structure| structure.name == name);
+    self.function(
+      chunk,
+      "self".to_string(),
+      structure,
+      parameters,
+      fn_declaration.body,
+    )
+  }
+
+  // Slot 0 holds the callee, or the receiver of a method, and the parameters
+  // follow it.
+  fn function(
+    &mut self,
+    mut chunk: Chunk,
+    callee: String,
+    structure: Option<usize>,
+    parameters: Vec<String>,
+    body: Block,
+  ) -> Result<Chunk, GenerateError> {
+    let mut scope = Scope {
+      locals: Vec::new(),
+      captures: Vec::new(),
+      depth: 0,
+    };
+    chunk.add_local(callee.clone());
+    scope.bind(callee, false, structure);
+    for parameter in parameters {
+      chunk.add_parameter(parameter.clone());
+      scope.bind(parameter, false, None);
+    }
+
+    self.scopes.push(scope);
+    // The frame is discarded on return, so the locals of the body are left on
+    // the stack.
+    self.block_inner(&mut chunk, body)?;
     chunk.emit(Instruction::Return);
-    chunk
+    for capture in self.scopes.pop().unwrap().captures {
+      chunk.add_capture(capture);
+    }
+    Ok(chunk)
+  }
+
+  // The locals bound within the block go out of scope at its end, and are
+  // popped off the stack from beneath the value of the block, freeing their
+  // slots for later locals.
+  fn block(
+    &mut self,
+    chunk: &mut Chunk,
+    block: Block,
+  ) -> Result<(), GenerateError> {
+    let start = self.scope().locals.len();
+    let depth = self.scope().depth;
+    self.block_inner(chunk, block)?;
+
+    let scope = self.scope_mut();
+    let len = scope.locals.len() - start;
+    scope.locals.truncate(start);
+    scope.depth = depth;
+    if len > 0 {
+      chunk.emit(Instruction::PopN);
+      chunk.emit_operand(len as u64);
+    }
+
+    Ok(())
   }
 
-  fn block(&mut self, chunk: &mut Chunk, block: Block) {
+  fn block_inner(
+    &mut self,
+    chunk: &mut Chunk,
+    block: Block,
+  ) -> Result<(), GenerateError> {
     let requires_unit_return = block
       .statements
       .last()
@@ -39,35 +362,65 @@ impl Generator {
         }
       })
       .unwrap_or(true);
-    for statement in block.statements {
-      self.statement(chunk, statement)
+    let len = block.statements.len();
+    for (index, statement) in block.statements.into_iter().enumerate() {
+      self.statement(chunk, statement, index + 1 == len)?;
     }
     if requires_unit_return {
       chunk.emit(Instruction::PushUnit);
     }
+
+    Ok(())
   }
 
-  fn statement(&mut self, chunk: &mut Chunk, statement: Statement) {
+  fn statement(
+    &mut self,
+    chunk: &mut Chunk,
+    statement: Statement,
+    is_last: bool,
+  ) -> Result<(), GenerateError> {
+    // Every statement starts with a location, so that there is an instruction
+    // to break at on each line that holds one.
+    let token = match &statement {
+      Statement::Bind(bind_statement) => Some(&bind_statement.keyword),
+      Statement::Expression(expression_statement) => {
+        first_token(&expression_statement.expression)
+      }
+    };
+    if let Some(token) = token {
+      locate(chunk, token);
+    }
+
     match statement {
       Statement::Bind(bind_statement) => {
-        self.bind_statement(chunk, bind_statement);
+        self.bind_statement(chunk, bind_statement)?;
       }
       Statement::Expression(expression_statement) => {
-        self.expression_statement(chunk, expression_statement);
+        self.expression_statement(chunk, expression_statement, is_last)?;
       }
     }
+
+    Ok(())
   }
 
   fn bind_statement(
     &mut self,
     chunk: &mut Chunk,
     bind_statement: BindStatement,
-  ) {
-    self.expression(chunk, bind_statement.expression);
+  ) -> Result<(), GenerateError> {
+    let structure = self
+      .struct_type(&bind_statement.expression)
+      .filter(|_| !bind_statement.is_mut);
+    self.expression(chunk, bind_statement.expression)?;
     if let Pattern::Literal(token) = bind_statement.pattern {
       if let Token::Identifier = token.base() {
-        chunk.add_local(token.span().as_str().to_string());
-        return;
+        let name = token.span().to_string();
+        let slot =
+          self
+            .scope_mut()
+            .bind(name.clone(), bind_statement.is_mut, structure);
+        chunk.set_local(slot, name);
+        return Ok(());
       }
     }
     panic!("cannot assign to non-identifier");
@@ -77,19 +430,58 @@ impl Generator {
     &mut self,
     chunk: &mut Chunk,
     expression_statement: ExpressionStatement,
-  ) {
-    self.expression(chunk, expression_statement.expression);
-    if expression_statement.has_semicolon {
+    is_last: bool,
+  ) -> Result<(), GenerateError> {
+    self.expression(chunk, expression_statement.expression)?;
+    // Only the last statement of a block can give the block its value, even if
+    // the others are block-like expressions written without a semicolon.
+    if expression_statement.has_semicolon || !is_last {
       chunk.emit(Instruction::Pop);
     }
+
+    Ok(())
   }
 
-  fn expression(&mut self, chunk: &mut Chunk, expression: Expression) {
-    match expression {
-      Expression::Assign(_) => {}
-      Expression::Path(_) => {}
-      Expression::Call(_) => {}
-      Expression::Access(_) => {}
+  fn expression(
+    &mut self,
+    chunk: &mut Chunk,
+    expression: Expression,
+  ) -> Result<(), GenerateError> {
+    // The temporaries pushed while evaluating the expression are consumed by
+    // it, and its value is counted by the caller if it stays on the stack.
+    let depth = self.scope().depth;
+    let result = match expression {
+      Expression::Assign(assign_expression) => {
+        self.assign_expression(chunk, assign_expression)
+      }
+      // A path is only meaningful as the callee of a native.
+      Expression::Path(path) => {
+        Err(GenerateError::UnknownLocal(path_span(&path)))
+      }
+      Expression::List(list_expression) => {
+        self.list_expression(chunk, list_expression)
+      }
+      Expression::Map(map_expression) => {
+        self.map_expression(chunk, map_expression)
+      }
+      Expression::Call(call_expression) => {
+        self.call_expression(chunk, call_expression)
+      }
+      Expression::Index(index_expression) => {
+        self.index_expression(chunk, index_expression)
+      }
+      Expression::IndexAssign(index_assign_expression) => {
+        self.index_assign_expression(chunk, index_assign_expression)
+      }
+      Expression::Struct(struct_expression) => {
+        self.struct_expression(chunk, struct_expression)
+      }
+      Expression::Access(access_expression) => {
+        self.access_expression(chunk, access_expression)
+      }
+      Expression::AccessAssign(access_assign_expression) => {
+        self.access_assign_expression(chunk, access_assign_expression)
+      }
       Expression::Binary(binary_expression) => {
         self.binary_expression(chunk, binary_expression)
       }
@@ -103,160 +495,827 @@ impl Generator {
       Expression::While(while_expression) => {
         self.while_expression(chunk, while_expression)
       }
-    }
+      Expression::Try(try_expression) => {
+        self.try_expression(chunk, try_expression)
+      }
+      Expression::Throw(throw_expression) => {
+        self.throw_expression(chunk, throw_expression)
+      }
+      Expression::Fn(fn_expression) => self.fn_expression(chunk, fn_expression),
+    };
+    self.scope_mut().depth = depth;
+    result
+  }
+
+  // Generates an expression whose value stays on the stack while the code
+  // after it is generated, until the enclosing expression is done with it.
+  fn operand(
+    &mut self,
+    chunk: &mut Chunk,
+    expression: Expression,
+  ) -> Result<(), GenerateError> {
+    self.expression(chunk, expression)?;
+    self.scope_mut().depth += 1;
+    Ok(())
   }
 
   fn binary_expression(
     &mut self,
     chunk: &mut Chunk,
     binary_expression: BinaryExpression,
-  ) {
-    self.expression(chunk, *binary_expression.left_operand);
-    self.expression(chunk, *binary_expression.right_operand);
+  ) -> Result<(), GenerateError> {
+    if let Token::AmpAmp | Token::PipePipe = binary_expression.operator.base() {
+      return self.logical_expression(chunk, binary_expression);
+    }
+
+    self.operand(chunk, *binary_expression.left_operand)?;
+    self.operand(chunk, *binary_expression.right_operand)?;
 
     let instruction = match binary_expression.operator.base() {
       Token::Plus => Instruction::Add,
       Token::Dash => Instruction::Subtract,
       Token::Star => Instruction::Multiply,
       Token::Slash => Instruction::Divide,
+      Token::Percent => Instruction::Remainder,
+      Token::Amp => Instruction::BitAnd,
+      Token::Pipe => Instruction::BitOr,
+      Token::Caret => Instruction::BitXor,
+      Token::LessLess => Instruction::ShiftLeft,
+      Token::GreaterGreater => Instruction::ShiftRight,
       Token::Greater => Instruction::Greater,
       Token::GreaterEqual => Instruction::GreaterEqual,
       Token::Less => Instruction::Less,
       Token::LessEqual => Instruction::LessEqual,
       Token::EqualEqual => Instruction::Equal,
       Token::BangEqual => Instruction::NotEqual,
-      Token::AmpAmp => Instruction::And,
-      Token::PipePipe => Instruction::Or,
       _ => unreachable!("invalid operator in binary expression"),
     };
+    locate(chunk, &binary_expression.operator);
     chunk.emit(instruction);
+
+    Ok(())
+  }
+
+  // The right operand is only evaluated if the left one does not already
+  // decide the result, which is `false` for `&&` and `true` for `||`.
+  fn logical_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    binary_expression: BinaryExpression,
+  ) -> Result<(), GenerateError> {
+    let is_and = binary_expression.operator.base() == &Token::AmpAmp;
+
+    self.expression(chunk, *binary_expression.left_operand)?;
+    locate(chunk, &binary_expression.operator);
+    if is_and {
+      chunk.emit(Instruction::Not);
+    }
+    let jump_if = chunk.emit_jump(Instruction::JumpIf);
+
+    self.expression(chunk, *binary_expression.right_operand)?;
+    let jump = chunk.emit_jump(Instruction::Jump);
+
+    chunk.patch_jump(jump_if, chunk.code().len());
+    let constant = chunk.add_constant(Constant::Bool(!is_and)) as u64;
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant);
+
+    chunk.patch_jump(jump, chunk.code().len());
+
+    Ok(())
   }
 
   fn unary_expression(
     &mut self,
     chunk: &mut Chunk,
     unary_expression: UnaryExpression,
-  ) {
-    self.expression(chunk, *unary_expression.operand);
+  ) -> Result<(), GenerateError> {
+    self.expression(chunk, *unary_expression.operand)?;
 
     let instruction = match unary_expression.operator.base() {
       Token::Dash => Instruction::Negate,
       Token::Bang => Instruction::Not,
+      Token::Tilde => Instruction::BitNot,
       _ => unreachable!("invalid operator in unary expression"),
     };
+    locate(chunk, &unary_expression.operator);
     chunk.emit(instruction);
+
+    Ok(())
+  }
+
+  fn list_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    list_expression: ListExpression,
+  ) -> Result<(), GenerateError> {
+    let len = list_expression.elements.len() as u64;
+
+    for element in list_expression.elements {
+      self.operand(chunk, element)?;
+    }
+
+    chunk.emit(Instruction::MakeList);
+    chunk.emit_operand(len);
+
+    Ok(())
+  }
+
+  fn map_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    map_expression: MapExpression,
+  ) -> Result<(), GenerateError> {
+    let len = map_expression.entries.len() as u64;
+
+    for entry in map_expression.entries {
+      self.operand(chunk, entry.key)?;
+      self.operand(chunk, entry.value)?;
+    }
+
+    chunk.emit(Instruction::MakeMap);
+    chunk.emit_operand(len);
+
+    Ok(())
+  }
+
+  // The fields are evaluated in the order they are declared in rather than the
+  // order they are written in, since that is the order the record stores them.
+  fn struct_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    struct_expression: StructExpression,
+  ) -> Result<(), GenerateError> {
+    let name = struct_expression.name;
+    let index = self
+      .structs
+      .iter()
+      .position(|structure| structure.name == name.span().as_str())
+      .ok_or_else(|| GenerateError::UnknownStruct(name.span().clone()))?;
+    let fields = self.structs[index].fields.clone();
+
+    // A field initialized twice is reported like one that is not declared.
+    let mut initializers = struct_expression.fields;
+    for (position, initializer) in initializers.iter().enumerate() {
+      let field = initializer.name.span().as_str();
+      let is_repeated = initializers[..position]
+        .iter()
+        .any(|other| other.name.span().as_str() == field);
+      if is_repeated || !fields.iter().any(|other| other == field) {
+        return Err(GenerateError::UnknownField(
+          initializer.name.span().clone(),
+        ));
+      }
+    }
+
+    for field in fields {
+      let position = initializers
+        .iter()
+        .position(|initializer| initializer.name.span().as_str() == field)
+        .ok_or_else(|| GenerateError::MissingField(name.span().clone()))?;
+      let initializer = initializers.remove(position);
+      self.operand(chunk, initializer.value)?;
+    }
+
+    chunk.emit(Instruction::MakeRecord);
+    chunk.emit_operand(index as u64);
+
+    Ok(())
+  }
+
+  fn access_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    access_expression: AccessExpression,
+  ) -> Result<(), GenerateError> {
+    self.check_field(&access_expression.receiver, &access_expression.field)?;
+    self.expression(chunk, *access_expression.receiver)?;
+
+    let field = chunk.add_constant(Constant::String(
+      access_expression.field.span().to_string(),
+    ));
+    locate(chunk, &access_expression.field);
+    chunk.emit(Instruction::GetField);
+    chunk.emit_operand(field as u64);
+
+    Ok(())
+  }
+
+  fn access_assign_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    access_assign_expression: AccessAssignExpression,
+  ) -> Result<(), GenerateError> {
+    self.check_field(
+      &access_assign_expression.receiver,
+      &access_assign_expression.field,
+    )?;
+    self.operand(chunk, *access_assign_expression.receiver)?;
+    self.operand(chunk, *access_assign_expression.operand)?;
+
+    let field = chunk.add_constant(Constant::String(
+      access_assign_expression.field.span().to_string(),
+    ));
+    locate(chunk, &access_assign_expression.field);
+    chunk.emit(Instruction::SetField);
+    chunk.emit_operand(field as u64);
+
+    Ok(())
+  }
+
+  // Checks that the field exists if the struct of the receiver is known.
+  fn check_field(
+    &self,
+    receiver: &Expression,
+    field: &Spanned<Token>,
+  ) -> Result<(), GenerateError> {
+    match self.struct_type(receiver) {
+      Some(index)
+        if !self.structs[index]
+          .fields
+          .iter()
+          .any(|other| other == field.span().as_str()) =>
+      {
+        Err(GenerateError::UnknownField(field.span().clone()))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  // Returns the struct the value of the expression is known to be a record of,
+  // if it is a struct expression or a local whose struct is known.
+  fn struct_type(&self, expression: &Expression) -> Option<usize> {
+    match expression {
+      Expression::Struct(struct_expression) => {
+        let name = struct_expression.name.span().as_str();
+        self
+          .structs
+          .iter()
+          .position(|structure| structure.name == name)
+      }
+      Expression::Literal(token) if token.base() == &Token::Identifier => {
+        self.scope().local(token.span().as_str())?.structure
+      }
+      _ => None,
+    }
+  }
+
+  // Assigns to a local of the function being generated, or to one captured
+  // from an enclosing function, either of which must be declared `mut`.
+  fn assign_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    assign_expression: AssignExpression,
+  ) -> Result<(), GenerateError> {
+    let token = match &assign_expression.pattern {
+      Pattern::Literal(token) if token.base() == &Token::Identifier => token,
+      _ => panic!("cannot assign to non-identifier"),
+    };
+    let identifier = token.span().to_string();
+    self.expression(chunk, *assign_expression.operand)?;
+
+    // The local being assigned to is the innermost one of the name in the
+    // nearest function that binds it.
+    let is_mut = self
+      .scopes
+      .iter()
+      .rev()
+      .find_map(|scope| scope.local(&identifier).map(|local| local.is_mut));
+    match is_mut {
+      Some(true) => {}
+      Some(false) => {
+        return Err(GenerateError::ImmutableLocal(token.span().clone()))
+      }
+      None => return Err(GenerateError::UnknownLocal(token.span().clone())),
+    }
+
+    let level = self.scopes.len() - 1;
+    if let Some(local) = self.scope().local(&identifier) {
+      chunk.emit(Instruction::SetLocal);
+      chunk.emit_operand(local.slot as u64);
+    } else if let Some(index) = self.resolve_capture(level, &identifier) {
+      chunk.emit(Instruction::SetUpvalue);
+      chunk.emit_operand(index as u64);
+    }
+
+    Ok(())
+  }
+
+  fn index_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    index_expression: IndexExpression,
+  ) -> Result<(), GenerateError> {
+    let token = first_token(&index_expression.receiver).cloned();
+    self.operand(chunk, *index_expression.receiver)?;
+    self.operand(chunk, *index_expression.index)?;
+    if let Some(token) = token {
+      locate(chunk, &token);
+    }
+    chunk.emit(Instruction::Index);
+
+    Ok(())
   }
 
-  fn literal_expression(&mut self, chunk: &mut Chunk, token: Spanned<Token>) {
+  fn index_assign_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    index_assign_expression: IndexAssignExpression,
+  ) -> Result<(), GenerateError> {
+    let token = first_token(&index_assign_expression.receiver).cloned();
+    self.operand(chunk, *index_assign_expression.receiver)?;
+    self.operand(chunk, *index_assign_expression.index)?;
+    self.operand(chunk, *index_assign_expression.operand)?;
+    if let Some(token) = token {
+      locate(chunk, &token);
+    }
+    chunk.emit(Instruction::SetIndex);
+
+    Ok(())
+  }
+
+  fn call_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    call_expression: CallExpression,
+  ) -> Result<(), GenerateError> {
+    let arity = call_expression.arguments.len() as u64;
+    let token = first_token(&call_expression.receiver).cloned();
+
+    // A call on an access expression is a method invocation on the receiver,
+    // which calls the value of a field if there is no method of the name.
+    if let Expression::Access(access_expression) = *call_expression.receiver {
+      if let Some(index) = self.struct_type(&access_expression.receiver) {
+        let structure = &self.structs[index];
+        let name = access_expression.field.span().as_str();
+        if !structure.methods.contains_key(name)
+          && !structure.fields.iter().any(|field| field == name)
+        {
+          return Err(GenerateError::UnknownMethod(
+            access_expression.field.span().clone(),
+          ));
+        }
+      }
+      self.operand(chunk, *access_expression.receiver)?;
+      for argument in call_expression.arguments {
+        self.operand(chunk, argument)?;
+      }
+
+      let method = chunk.add_constant(Constant::String(
+        access_expression.field.span().to_string(),
+      ));
+      locate(chunk, &access_expression.field);
+      chunk.emit(Instruction::Invoke);
+      chunk.emit_operand(method as u64);
+      chunk.emit_operand(arity);
+      return Ok(());
+    }
+
+    // `print` is built into the machine rather than provided as a native.
+    if self.is_intrinsic(&call_expression.receiver, "print") && arity == 1 {
+      for argument in call_expression.arguments {
+        self.expression(chunk, argument)?;
+      }
+      if let Some(token) = token {
+        locate(chunk, &token);
+      }
+      chunk.emit(Instruction::Print);
+      return Ok(());
+    }
+
+    if let Some(native) = self.native(&call_expression.receiver) {
+      for argument in call_expression.arguments {
+        self.operand(chunk, argument)?;
+      }
+
+      let native = chunk.add_constant(Constant::String(native));
+      if let Some(token) = token {
+        locate(chunk, &token);
+      }
+      chunk.emit(Instruction::CallNative);
+      chunk.emit_operand(native as u64);
+      chunk.emit_operand(arity);
+      return Ok(());
+    }
+
+    // Only natives are called through a path.
+    if let Expression::Path(path) = call_expression.receiver.as_ref() {
+      return Err(GenerateError::UnknownNative(path_span(path)));
+    }
+
+    self.operand(chunk, *call_expression.receiver)?;
+    for argument in call_expression.arguments {
+      self.operand(chunk, argument)?;
+    }
+
+    if let Some(token) = token {
+      locate(chunk, &token);
+    }
+    chunk.emit(Instruction::Call);
+    chunk.emit_operand(arity);
+
+    Ok(())
+  }
+
+  fn is_intrinsic(&mut self, receiver: &Expression, name: &str) -> bool {
+    match receiver {
+      Expression::Literal(token) if token.span().as_str() == name => {
+        !self.is_defined(name)
+      }
+      _ => false,
+    }
+  }
+
+  // Returns whether the identifier refers to a local, a capture or a function.
+  fn is_defined(&mut self, identifier: &str) -> bool {
+    self.scope().local(identifier).is_some()
+      || self
+        .resolve_capture(self.scopes.len() - 1, identifier)
+        .is_some()
+      || self.functions.contains_key(identifier)
+  }
+
+  // Resolves the callee to the name of a native, if it is either a path to one
+  // or an identifier that is not shadowed by a local or a function.
+  fn native(&mut self, receiver: &Expression) -> Option<String> {
+    let path = match receiver {
+      Expression::Literal(token) if token.base() == &Token::Identifier => {
+        let identifier = token.span().as_str();
+        if self.is_defined(identifier) {
+          return None;
+        }
+        identifier.to_string()
+      }
+      Expression::Path(Path { components }) => components
+        .iter()
+        .map(|component| component.span().as_str())
+        .collect::<Vec<&str>>()
+        .join("::"),
+      _ => return None,
+    };
+
+    let path = match path.split_once("::") {
+      Some((first, rest)) => match self.uses.get(first) {
+        Some(prefix) => join_path(prefix, rest),
+        None => path,
+      },
+      None => self.uses.get(&path).cloned().unwrap_or(path),
+    };
+
+    if self.natives.contains(&path) {
+      Some(path)
+    } else {
+      None
+    }
+  }
+
+  fn literal_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    token: Spanned<Token>,
+  ) -> Result<(), GenerateError> {
     let constant = match token.base() {
       Token::Int => Constant::Int(token.span().as_str().parse().unwrap()),
       Token::Float => Constant::Float(token.span().as_str().parse().unwrap()),
+      Token::String => Constant::String(string_literal(token.span().as_str())),
       Token::True => Constant::Bool(true),
       Token::False => Constant::Bool(false),
       Token::Identifier => {
         let identifier = token.span().as_str().to_string();
-        let index = chunk
-          .local(&identifier)
-          .expect(format!("local '{}' not defined", identifier).as_str())
-          as u64;
-        chunk.emit(Instruction::PushLocal);
-        chunk.emit_bytes(index.to_le_bytes());
-        return;
+        if let Some(local) = self.scope().local(&identifier) {
+          chunk.emit(Instruction::PushLocal);
+          chunk.emit_operand(local.slot as u64);
+        } else if let Some(index) =
+          self.resolve_capture(self.scopes.len() - 1, &identifier)
+        {
+          chunk.emit(Instruction::GetUpvalue);
+          chunk.emit_operand(index as u64);
+        } else {
+          let index =
+            *self.functions.get(&identifier).ok_or_else(|| {
+              GenerateError::UnknownLocal(token.span().clone())
+            })? as u64;
+          chunk.emit(Instruction::PushFunction);
+          chunk.emit_operand(index);
+        }
+        return Ok(());
       }
       _ => unreachable!("invalid token passed to literal expression"),
     };
     let index = chunk.add_constant(constant) as u64;
     chunk.emit(Instruction::PushConstant);
-    chunk.emit_bytes(index.to_le_bytes());
+    chunk.emit_operand(index);
+
+    Ok(())
   }
 
-  fn if_expression(&mut self, chunk: &mut Chunk, if_expression: IfExpression) {
-    self.expression(chunk, *if_expression.condition);
+  fn if_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    if_expression: IfExpression,
+  ) -> Result<(), GenerateError> {
+    self.expression(chunk, *if_expression.condition)?;
 
-    chunk.emit(Instruction::JumpIf);
-    let jump_if_offset = chunk.emit_bytes(0u64.to_le_bytes());
+    let jump_if = chunk.emit_jump(Instruction::JumpIf);
 
     if let Some(else_body) = if_expression.else_body {
       match else_body {
         ElseBody::If(if_expression) => {
-          self.if_expression(chunk, *if_expression)
+          self.if_expression(chunk, *if_expression)?
         }
-        ElseBody::Else(block) => self.block(chunk, block),
+        ElseBody::Else(block) => self.block(chunk, block)?,
       }
+    } else {
+      chunk.emit(Instruction::PushUnit);
     }
 
-    chunk.emit(Instruction::Jump);
-    let jump_offset = chunk.emit_bytes(0u64.to_le_bytes());
+    let jump = chunk.emit_jump(Instruction::Jump);
 
-    self.block(chunk, if_expression.body);
+    chunk.patch_jump(jump_if, chunk.code().len());
+    self.block(chunk, if_expression.body)?;
 
-    let u64_bytes_len = 0u64.to_le_bytes().len() as u64;
-    chunk.patch_bytes(
-      jump_if_offset,
-      (jump_offset as u64 + u64_bytes_len).to_le_bytes(),
-    );
-    chunk.patch_bytes(jump_offset, (chunk.code().len() as u64).to_le_bytes());
+    chunk.patch_jump(jump, chunk.code().len());
+
+    Ok(())
+  }
+
+  fn fn_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    fn_expression: FnExpression,
+  ) -> Result<(), GenerateError> {
+    let parameters = fn_expression
+      .parameters
+      .iter()
+      .map(|parameter| parameter.span().to_string())
+      .collect();
+    // Slot 0 holds the closure itself, which has no name to refer to it by.
+    let function = self.function(
+      Chunk::new(),
+      String::new(),
+      None,
+      parameters,
+      fn_expression.body,
+    )?;
+
+    let index = (self.declarations_len + self.closures.len()) as u64;
+    self.closures.push(function);
+
+    chunk.emit(Instruction::MakeClosure);
+    chunk.emit_operand(index);
+
+    Ok(())
+  }
+
+  fn scope(&self) -> &Scope {
+    self.scopes.last().expect("no function being generated")
+  }
+
+  fn scope_mut(&mut self) -> &mut Scope {
+    self.scopes.last_mut().expect("no function being generated")
+  }
+
+  // Resolves the identifier to a capture of the function at the given level,
+  // capturing it through each enclosing function in between if necessary.
+  fn resolve_capture(
+    &mut self,
+    level: usize,
+    identifier: &str,
+  ) -> Option<usize> {
+    if level == 0 {
+      return None;
+    }
+
+    let capture = if let Some(local) = self.scopes[level - 1].local(identifier)
+    {
+      Capture {
+        is_local: true,
+        index: local.slot,
+      }
+    } else {
+      Capture {
+        is_local: false,
+        index: self.resolve_capture(level - 1, identifier)?,
+      }
+    };
+
+    let captures = &mut self.scopes[level].captures;
+    if let Some(index) = captures.iter().position(|&other| other == capture) {
+      return Some(index);
+    }
+    captures.push(capture);
+    Some(captures.len() - 1)
   }
 
   fn while_expression(
     &mut self,
     chunk: &mut Chunk,
     while_expression: WhileExpression,
-  ) {
-    self.expression(chunk, *while_expression.condition);
+  ) -> Result<(), GenerateError> {
+    let start = chunk.code().len();
+    self.expression(chunk, *while_expression.condition)?;
 
     chunk.emit(Instruction::Not);
-    chunk.emit(Instruction::JumpIf);
-    let jump_if_offset = chunk.emit_bytes(0u64.to_le_bytes());
+    let jump_if = chunk.emit_jump(Instruction::JumpIf);
 
-    self.block(chunk, while_expression.body);
+    self.block(chunk, while_expression.body)?;
 
     chunk.emit(Instruction::Pop);
 
-    let u64_bytes_len = 0u64.to_le_bytes().len() as u64;
-    chunk.emit(Instruction::Jump);
-    chunk.emit_bytes((jump_if_offset as u64 + u64_bytes_len).to_le_bytes());
+    // Padded like forward jumps, so that the loop disassembles to a label.
+    let jump = chunk.emit_jump(Instruction::Jump);
+    chunk.patch_jump(jump, start);
 
-    chunk
-      .patch_bytes(jump_if_offset, (chunk.code().len() as u64).to_le_bytes());
+    chunk.patch_jump(jump_if, chunk.code().len());
 
     chunk.emit(Instruction::PushUnit);
+
+    Ok(())
+  }
+
+  // The handler covers only the body, so that values thrown within the catch
+  // body go to an enclosing handler. The handler unwinds the stack to its depth
+  // before the body, where the thrown value is bound to a local for the catch
+  // body, and popped from beneath its value afterwards.
+  fn try_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    try_expression: TryExpression,
+  ) -> Result<(), GenerateError> {
+    let start = chunk.code().len();
+    self.block(chunk, try_expression.body)?;
+    let end = chunk.code().len();
+    let jump = chunk.emit_jump(Instruction::Jump);
+
+    chunk.add_handler(Handler {
+      start,
+      end,
+      target: chunk.code().len(),
+    });
+
+    let name = try_expression.name.span().to_string();
+    let slot = self.scope_mut().bind(name.clone(), false, None);
+    chunk.set_local(slot, name);
+    self.block(chunk, try_expression.catch_body)?;
+    let scope = self.scope_mut();
+    scope.locals.pop();
+    scope.depth -= 1;
+    chunk.emit(Instruction::PopN);
+    chunk.emit_operand(1);
+
+    chunk.patch_jump(jump, chunk.code().len());
+
+    Ok(())
+  }
+
+  fn throw_expression(
+    &mut self,
+    chunk: &mut Chunk,
+    throw_expression: ThrowExpression,
+  ) -> Result<(), GenerateError> {
+    self.expression(chunk, *throw_expression.operand)?;
+    locate(chunk, &throw_expression.keyword);
+    chunk.emit(Instruction::Throw);
+
+    Ok(())
+  }
+}
+
+// Sets the location of the next instructions to the start of the token. Only
+// the start of each statement and the instructions that can fail at runtime
+// are given a location.
+fn locate(chunk: &mut Chunk, token: &Spanned<Token>) {
+  let (line, column) = token.span().line_column();
+  chunk.add_location(Location { line, column });
+}
+
+// Returns the leftmost token of the expression, which stands in for the
+// location of expressions that have no token of their own.
+fn first_token(expression: &Expression) -> Option<&Spanned<Token>> {
+  match expression {
+    Expression::Literal(token) => Some(token),
+    Expression::Path(path) => path.components.first(),
+    Expression::List(list_expression) => Some(&list_expression.bracket),
+    Expression::Map(map_expression) => Some(&map_expression.bracket),
+    Expression::Struct(struct_expression) => Some(&struct_expression.name),
+    Expression::Access(access_expression) => {
+      first_token(&access_expression.receiver)
+    }
+    Expression::Call(call_expression) => first_token(&call_expression.receiver),
+    Expression::Index(index_expression) => {
+      first_token(&index_expression.receiver)
+    }
+    Expression::Unary(unary_expression) => Some(&unary_expression.operator),
+    Expression::Binary(binary_expression) => {
+      first_token(&binary_expression.left_operand)
+    }
+    Expression::Assign(assign_expression) => match &assign_expression.pattern {
+      Pattern::Literal(token) => Some(token),
+      _ => None,
+    },
+    Expression::IndexAssign(index_assign_expression) => {
+      first_token(&index_assign_expression.receiver)
+    }
+    Expression::AccessAssign(access_assign_expression) => {
+      first_token(&access_assign_expression.receiver)
+    }
+    Expression::If(if_expression) => Some(&if_expression.keyword),
+    Expression::While(while_expression) => Some(&while_expression.keyword),
+    Expression::Try(try_expression) => Some(&try_expression.keyword),
+    Expression::Throw(throw_expression) => Some(&throw_expression.keyword),
+    Expression::Fn(fn_expression) => Some(&fn_expression.keyword),
+  }
+}
+
+// Returns the span from the first component of the path to its last.
+fn path_span(path: &Path) -> Span {
+  let first = path.components.first().expect("empty path");
+  let last = path.components.last().expect("empty path");
+  Span::combine(first.span(), last.span())
+}
+
+fn join_path(prefix: &str, name: &str) -> String {
+  if prefix.is_empty() {
+    name.to_string()
+  } else {
+    format!("{}::{}", prefix, name)
   }
 }
 
 #[cfg(test)]
 mod tests {
+  use std::{cell::RefCell, io, rc::Rc};
+
   use oma::{
-    executable::{Chunk, Constant},
+    assembly,
+    executable::{Chunk, Constant, Executable, Location},
+    heap::Object,
     instruction::Instruction,
+    machine::{Error, Machine, Trace},
+    value::Value,
   };
 
   use crate::parse::Parser;
 
-  use super::Generator;
+  use super::{GenerateError, Generator};
+
+  fn generate(source: &str) -> Result<Executable, GenerateError> {
+    let file = Parser::new(source).parse().expect("failed to parse");
+    Generator::new().generate(file)
+  }
+
+  // Generates the source with the natives registered with the machine, and
+  // executes it.
+  fn execute(machine: &mut Machine, source: &str) -> Result<Value, Trace> {
+    let file = Parser::new(source).parse().expect("failed to parse");
+    let mut generator = Generator::new();
+    for native in machine.natives() {
+      generator.register_native(native);
+    }
+    let executable = generator.generate(file).expect("failed to generate");
+    machine.execute(&executable)
+  }
+
+  // Garbage is collected at every allocation, so that a value the generated
+  // code fails to keep reachable is freed while it is still in use.
+  fn run(source: &str) -> Value {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+    execute(&mut machine, source).unwrap()
+  }
 
   #[test]
   fn addition() {
-    let parser = Parser::new("{ 1 + 2 + 3; }");
-    let block = parser.parse();
-    let generator = Generator::new();
-
     let mut chunk = Chunk::new();
+    chunk.set_name("main".to_string());
+    chunk.add_local("main".to_string());
 
+    chunk.add_location(Location {
+      line: 1,
+      column: 13,
+    });
     let constant = chunk.add_constant(Constant::Int(1));
     chunk.emit(Instruction::PushConstant);
-    chunk.emit_bytes(constant.to_le_bytes());
+    chunk.emit_operand(constant as u64);
 
     let constant = chunk.add_constant(Constant::Int(2));
     chunk.emit(Instruction::PushConstant);
-    chunk.emit_bytes(constant.to_le_bytes());
+    chunk.emit_operand(constant as u64);
 
+    chunk.add_location(Location {
+      line: 1,
+      column: 15,
+    });
     chunk.emit(Instruction::Add);
 
     let constant = chunk.add_constant(Constant::Int(3));
     chunk.emit(Instruction::PushConstant);
-    chunk.emit_bytes(constant.to_le_bytes());
+    chunk.emit_operand(constant as u64);
 
+    chunk.add_location(Location {
+      line: 1,
+      column: 19,
+    });
     chunk.emit(Instruction::Add);
 
     chunk.emit(Instruction::Pop);
@@ -265,6 +1324,759 @@ mod tests {
 
     chunk.emit(Instruction::Return);
 
-    assert_eq!(generator.generate(block), chunk);
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert_eq!(
+      generate("fn main() { 1 + 2 + 3; }").expect("failed to generate"),
+      executable
+    );
+  }
+
+  #[test]
+  fn recursion() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          fib(10)
+        }
+
+        fn fib(n) {
+          if n < 2 {
+            n
+          } else {
+            fib(n - 1) + fib(n - 2)
+          }
+        }
+        ",
+      ),
+      Value::Int(55)
+    );
+  }
+
+  #[test]
+  fn string() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          \"foo\" + \"bar\" == \"foobar\" && \"a\\\"b\\\\\\n\" == \"a\\\"b\\\\\\n\"
+        }
+        ",
+      ),
+      Value::Bool(true)
+    );
+  }
+
+  #[test]
+  fn bitwise() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          (1 | 6 ^ 3 & 5 << 1) + (-17 >> 2 % 3) * 10 + (17 % 5 - ~1) * 100
+        }
+        ",
+      ),
+      Value::Int(355)
+    );
+  }
+
+  #[test]
+  fn control_flow() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let counter = [0, 0];
+          while counter[0] < 5 {
+            if counter[0] == 2 {
+              counter[1] = 10;
+            }
+            counter[0] = counter[0] + 1;
+          }
+          let total = counter[0] + counter[1];
+          total
+        }
+        ",
+      ),
+      Value::Int(15)
+    );
+  }
+
+  #[test]
+  fn assignment() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let mut total = 0;
+          let mut count = 0;
+          let add = fn(n) { total = total + n; };
+          while count < 5 {
+            count = count + 1;
+            add(count);
+          }
+          total
+        }
+        ",
+      ),
+      Value::Int(15)
+    );
+  }
+
+  #[test]
+  fn assignment_immutable() {
+    assert!(matches!(
+      generate("fn main() { let count = 0; count = 1; }"),
+      Err(GenerateError::ImmutableLocal(span)) if span.as_str() == "count"
+    ));
+
+    assert!(matches!(
+      generate("fn main() { let count = 0; let f = fn() { count = 1; }; }"),
+      Err(GenerateError::ImmutableLocal(span)) if span.as_str() == "count"
+    ));
+
+    assert!(matches!(
+      generate("fn main() { count = 1; }"),
+      Err(GenerateError::UnknownLocal(span)) if span.as_str() == "count"
+    ));
+
+    assert!(matches!(
+      generate("fn main() { count }"),
+      Err(GenerateError::UnknownLocal(span)) if span.as_str() == "count"
+    ));
+  }
+
+  #[test]
+  fn unknown_path() {
+    assert!(matches!(
+      generate("fn main() { let a = 5; let b = 6; foo::bar; a }"),
+      Err(GenerateError::UnknownLocal(span)) if span.as_str() == "foo::bar"
+    ));
+  }
+
+  #[test]
+  fn shadowing() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let x = 1;
+          let y = if true {
+            let x = 10;
+            let z = x + 1;
+            z
+          } else {
+            0
+          };
+          let x = x + y;
+          x
+        }
+        ",
+      ),
+      Value::Int(12)
+    );
+  }
+
+  #[test]
+  fn block_locals() {
+    // Each iteration pops its locals, closing over the one captured.
+    let mut machine = Machine::new();
+    machine.set_max_stack(Some(8));
+    let result = execute(
+      &mut machine,
+      "
+      fn main() {
+        let fns = [0, 0, 0];
+        let mut i = 0;
+        while i < 3 {
+          let j = i * 10;
+          let k = j;
+          fns[i] = fn() { k };
+          i = i + 1;
+        }
+        let total = fns[0]() + fns[1]() + fns[2]();
+        total
+      }
+      ",
+    );
+    assert_eq!(result.unwrap(), Value::Int(30));
+  }
+
+  #[test]
+  fn temporary_locals() {
+    // Locals bound within operands are placed above the values already pushed
+    // for the enclosing expression.
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let base = 7;
+          let list = [base, if true { let a = 5; a + base } else { 0 }];
+          let sum = base + pair(0, if true { let c = 2; c } else { 0 });
+          let paired = pair(base, if true { let a = 5; a } else { 0 });
+          let applied = apply(base, if true {
+            let a = 3;
+            fn() { a * 10 }
+          } else {
+            fn() { 0 }
+          });
+          paired + list[1] + sum + applied
+        }
+
+        fn pair(x, y) {
+          x * 1000 + y
+        }
+
+        fn apply(x, f) {
+          x + f()
+        }
+        ",
+      ),
+      Value::Int(7063)
+    );
+  }
+
+  #[test]
+  fn closure() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let add = adder(40);
+          add(2)
+        }
+
+        fn adder(x) {
+          fn(y) {
+            let z = 0;
+            let sum = fn() { x + y + z };
+            sum()
+          }
+        }
+        ",
+      ),
+      Value::Int(42)
+    );
+  }
+
+  #[test]
+  fn list() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let xs = [1, 2, [3]];
+          xs[1] = xs[0] + xs[2][0];
+          xs[1]
+        }
+        ",
+      ),
+      Value::Int(4)
+    );
+  }
+
+  #[test]
+  fn map() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let empty = [:];
+          let names = [1: \"one\", true: \"yes\", \"two\": 2];
+          names[\"two\"] = names[\"two\"] + 1;
+          names[\"two\"]
+        }
+        ",
+      ),
+      Value::Int(3)
+    );
+  }
+
+  #[test]
+  fn record() {
+    assert_eq!(
+      run(
+        "
+        struct Point { x, y }
+
+        fn main() {
+          let point = Point { y: 2, x: 1 };
+          if point.x < point.y {
+            point.x = point.x + point.y;
+          }
+          point.x
+        }
+        ",
+      ),
+      Value::Int(3)
+    );
+  }
+
+  #[test]
+  fn record_errors() {
+    assert!(matches!(
+      generate("fn main() { Point { x: 1 } }"),
+      Err(GenerateError::UnknownStruct(span)) if span.as_str() == "Point"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x, y }
+        fn main() { Point { x: 1, z: 2 } }
+        ",
+      ),
+      Err(GenerateError::UnknownField(span)) if span.as_str() == "z"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x, y }
+        fn main() { Point { x: 1, y: 2, x: 3 } }
+        ",
+      ),
+      Err(GenerateError::UnknownField(span)) if span.as_str() == "x"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x, y }
+        fn main() { Point { x: 1 } }
+        ",
+      ),
+      Err(GenerateError::MissingField(span)) if span.as_str() == "Point"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x, y }
+        impl Point { fn norm(point) { 0 } }
+        fn main() {}
+        ",
+      ),
+      Err(GenerateError::MissingSelf(span)) if span.as_str() == "norm"
+    ));
+
+    assert!(matches!(
+      generate("impl Point { fn norm(self) { 0 } } fn main() {}"),
+      Err(GenerateError::UnknownStruct(span)) if span.as_str() == "Point"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x }
+        fn main() { let p = Point { x: 1 }; p.y }
+        ",
+      ),
+      Err(GenerateError::UnknownField(span)) if span.as_str() == "y"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x }
+        fn main() { let p = Point { x: 1 }; p.y = 2; }
+        ",
+      ),
+      Err(GenerateError::UnknownField(span)) if span.as_str() == "y"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x }
+        impl Point { fn get(self) { self.y } }
+        fn main() {}
+        ",
+      ),
+      Err(GenerateError::UnknownField(span)) if span.as_str() == "y"
+    ));
+
+    assert!(matches!(
+      generate(
+        "
+        struct Point { x }
+        fn main() { let p = Point { x: 1 }; p.norm() }
+        ",
+      ),
+      Err(GenerateError::UnknownMethod(span)) if span.as_str() == "norm"
+    ));
+
+    // The struct of a mutable local is not known, since it can be reassigned.
+    assert!(generate(
+      "
+      struct Point { x }
+      fn main() { let mut p = Point { x: 1 }; p.y }
+      ",
+    )
+    .is_ok());
+  }
+
+  #[test]
+  fn method() {
+    assert_eq!(
+      run(
+        "
+        impl Counter {
+          fn increment(self, by) {
+            self.count = self.count + by;
+            self
+          }
+
+          fn apply(self) {
+            self.step(self.count)
+          }
+        }
+
+        struct Counter { count, step }
+
+        fn main() {
+          let counter = Counter { count: 1, step: fn(x) { x * 10 } };
+          counter.increment(2).increment(3);
+          counter.apply()
+        }
+        ",
+      ),
+      Value::Int(60)
+    );
+  }
+
+  #[test]
+  fn native() {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+    machine.register_native("math::abs", 1, |_, arguments| {
+      match arguments[0] {
+        Value::Int(int) => Ok(Value::Int(int.abs())),
+        _ => Err(Error::InvalidType),
+      }
+    });
+    machine.register_native("math::pow", 2, |_, arguments| {
+      match (&arguments[0], &arguments[1]) {
+        (Value::Int(base), Value::Int(exponent)) => {
+          Ok(Value::Int(base.pow(*exponent as u32)))
+        }
+        _ => Err(Error::InvalidType),
+      }
+    });
+    machine.register_native("string::concat", 2, |machine, arguments| {
+      match (&arguments[0], &arguments[1]) {
+        (Value::String(left), Value::String(right)) => {
+          let heap = machine.heap();
+          let string = format!("{}{}", heap.string(*left), heap.string(*right));
+          Ok(Value::String(machine.allocate(Object::String(string))))
+        }
+        _ => Err(Error::InvalidType),
+      }
+    });
+    machine.register_native("string::length", 1, |machine, arguments| {
+      match arguments[0] {
+        Value::String(string) => {
+          Ok(Value::Int(machine.heap().string(string).len() as i64))
+        }
+        _ => Err(Error::InvalidType),
+      }
+    });
+
+    let result = execute(
+      &mut machine,
+      "
+      use math::{abs, pow};
+      use string;
+
+      fn main() {
+        let length = string::length(string::concat(\"foo\", \"bar\"));
+        pow(abs(0 - 2), length) + math::abs(0 - 1)
+      }
+      ",
+    );
+    assert_eq!(result.unwrap(), Value::Int(65));
+  }
+
+  #[derive(Clone)]
+  struct Output(Rc<RefCell<Vec<u8>>>);
+
+  impl io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn unknown_native() {
+    assert!(matches!(
+      generate("fn main() { math::abs(0 - 1) }"),
+      Err(GenerateError::UnknownNative(span)) if span.as_str() == "math::abs"
+    ));
+
+    assert!(matches!(
+      generate("use math; fn main() { math::abs(0 - 1) }"),
+      Err(GenerateError::UnknownNative(span)) if span.as_str() == "math::abs"
+    ));
+  }
+
+  #[test]
+  fn print() {
+    let output = Output(Rc::new(RefCell::new(Vec::new())));
+    let mut machine = Machine::new();
+    machine.set_output(output.clone());
+    let result = execute(
+      &mut machine,
+      "
+      fn main() {
+        let i = 0;
+        1 + 2;
+        print(\"foo\" + \"bar\");
+        print([i, i + 1]);
+        shadow(3)
+      }
+
+      fn shadow(print) {
+        print
+      }
+      ",
+    );
+    assert_eq!(result.unwrap(), Value::Int(3));
+    assert_eq!(output.0.borrow().as_slice(), b"foobar\n[0, 1]\n");
+  }
+
+  #[test]
+  fn short_circuit() {
+    let output = Output(Rc::new(RefCell::new(Vec::new())));
+    let mut machine = Machine::new();
+    machine.set_output(output.clone());
+    let result = execute(
+      &mut machine,
+      "
+      fn main() {
+        let x = 0;
+        let mut calls = 0;
+        let mark = fn(result) { calls = calls + 1; result };
+        print([x != 0 && 10 / x > 1, x == 0 || 10 / x > 1]);
+        print([false && mark(true), true || mark(false)]);
+        print([true && mark(false), false || mark(true)]);
+        calls
+      }
+      ",
+    );
+    assert_eq!(result.unwrap(), Value::Int(2));
+    assert_eq!(
+      output.0.borrow().as_slice(),
+      b"[false, true]\n[false, true]\n[false, true]\n"
+    );
+  }
+
+  #[test]
+  fn locations() {
+    let trace = execute(
+      &mut Machine::new(),
+      "
+      fn main() {
+        let x = 1;
+        half(x - \"foo\")
+      }
+
+      fn half(n) {
+        n / 2
+      }
+      ",
+    )
+    .unwrap_err();
+    assert!(matches!(trace.error, Error::InvalidType));
+    assert_eq!(
+      trace.frames[0].location,
+      Some(Location {
+        line: 4,
+        column: 16
+      })
+    );
+  }
+
+  #[test]
+  fn trace() {
+    let trace = execute(
+      &mut Machine::new(),
+      "
+      fn main() {
+        half(3)
+      }
+
+      fn half(n) {
+        n / \"two\"
+      }
+      ",
+    )
+    .unwrap_err();
+    assert!(matches!(trace.error, Error::InvalidType));
+
+    let frames: Vec<(&str, Option<Location>)> = trace
+      .frames
+      .iter()
+      .map(|frame| (frame.name.as_str(), frame.location))
+      .collect();
+    assert_eq!(
+      frames,
+      vec![
+        (
+          "half",
+          Some(Location {
+            line: 7,
+            column: 11
+          })
+        ),
+        ("main", Some(Location { line: 3, column: 9 })),
+      ]
+    );
+  }
+
+  #[test]
+  fn exceptions() {
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let inner = try {
+            half(3)
+          } catch error {
+            error + 1
+          };
+          let outer = try {
+            try {
+              half(5)
+            } catch error {
+              throw error * 10;
+            }
+          } catch error {
+            error + inner
+          };
+          let ok = try { half(8) } catch error { 0 };
+          outer * 100 + ok
+        }
+
+        fn half(n) {
+          if n % 2 == 1 {
+            throw n;
+          }
+          n / 2
+        }
+        ",
+      ),
+      Value::Int(5404)
+    );
+  }
+
+  #[test]
+  fn exception_temporaries() {
+    // The thrown value is caught with values of the enclosing expressions still
+    // on the stack.
+    assert_eq!(
+      run(
+        "
+        fn main() {
+          let list = [7, 8, try { throw 41; } catch e { e + 1 }];
+          let total = pair(list[2], try { pair(1, half(3)) } catch e {
+            let doubled = e * 2;
+            doubled
+          });
+          total + list[0] + list[1]
+        }
+
+        fn pair(x, y) {
+          x * 1000 + y
+        }
+
+        fn half(n) {
+          if n % 2 == 1 {
+            throw n;
+          }
+          n / 2
+        }
+        ",
+      ),
+      Value::Int(42021)
+    );
+  }
+
+  #[test]
+  fn uncaught() {
+    let trace = execute(
+      &mut Machine::new(),
+      "
+      fn main() {
+        try { half(4) } catch error { 0 };
+        half(3)
+      }
+
+      fn half(n) {
+        if n % 2 == 1 {
+          throw n;
+        }
+        n / 2
+      }
+      ",
+    )
+    .unwrap_err();
+    assert!(matches!(trace.error, Error::Uncaught(Value::Int(3))));
+
+    let frames: Vec<(&str, Option<Location>)> = trace
+      .frames
+      .iter()
+      .map(|frame| (frame.name.as_str(), frame.location))
+      .collect();
+    assert_eq!(
+      frames,
+      vec![
+        (
+          "half",
+          Some(Location {
+            line: 9,
+            column: 11
+          })
+        ),
+        ("main", Some(Location { line: 4, column: 9 })),
+      ]
+    );
+  }
+
+  #[test]
+  fn assembly() {
+    let executable = generate(
+      "
+      struct Counter { count }
+
+      impl Counter {
+        fn increment(self) {
+          self.count = self.count + 1
+        }
+      }
+
+      fn main() {
+        let counter = Counter { count: 0 };
+        let mut total = 0;
+        let add = fn(x) { total = total + x; };
+        while counter.count < 4 {
+          add(counter.increment());
+        }
+        let name = \"total\";
+        total
+      }
+      ",
+    )
+    .expect("failed to generate");
+
+    let disassembly = assembly::disassemble(&executable);
+    assert!(!disassembly.contains(".byte"));
+    assert_eq!(assembly::assemble(&disassembly), Ok(executable));
   }
 }
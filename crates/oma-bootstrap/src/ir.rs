@@ -13,6 +13,8 @@ pub struct PackageHeader {
   pub use_declarations: Vec<Path>,
   pub mod_headers: HashMap<usize, ModHeader>,
   pub fn_headers: HashMap<usize, usize>,
+  pub struct_headers: HashMap<usize, StructHeader>,
+  pub impl_headers: HashMap<usize, ImplHeader>,
 }
 
 #[derive(Debug)]
@@ -20,6 +22,18 @@ pub struct ModHeader {
   pub use_declarations: Vec<Path>,
   pub mod_headers: HashMap<usize, ModHeader>,
   pub fn_headers: HashMap<usize, usize>,
+  pub struct_headers: HashMap<usize, StructHeader>,
+  pub impl_headers: HashMap<usize, ImplHeader>,
+}
+
+#[derive(Debug)]
+pub struct StructHeader {
+  pub fields: Vec<usize>,
+}
+
+#[derive(Debug)]
+pub struct ImplHeader {
+  pub fn_headers: HashMap<usize, usize>,
 }
 
 #[derive(Debug)]
@@ -56,22 +70,62 @@ pub enum Expression {
   Block(Block),
   Literal(LiteralExpression),
   Path(Path),
+  List(ListExpression),
+  Map(MapExpression),
+  Struct(StructExpression),
   Access(AccessExpression),
   Call(CallExpression),
+  Invoke(InvokeExpression),
+  Index(IndexExpression),
+  Assign(AssignExpression),
+  IndexAssign(IndexAssignExpression),
+  AccessAssign(AccessAssignExpression),
   Unary(UnaryExpression),
   Binary(BinaryExpression),
   If(IfExpression),
   While(WhileExpression),
+  Try(TryExpression),
+  Throw(ThrowExpression),
+  Fn(FnExpression),
 }
 
 #[derive(Debug)]
 pub enum LiteralExpression {
   Int(i64),
   Float(i64),
+  String(String),
   Bool(bool),
   Identifier(usize),
 }
 
+#[derive(Debug)]
+pub struct ListExpression {
+  pub elements: Vec<Expression>,
+}
+
+#[derive(Debug)]
+pub struct MapExpression {
+  pub entries: Vec<MapEntry>,
+}
+
+#[derive(Debug)]
+pub struct MapEntry {
+  pub key: Expression,
+  pub value: Expression,
+}
+
+#[derive(Debug)]
+pub struct StructExpression {
+  pub name: usize,
+  pub fields: Vec<FieldInitializer>,
+}
+
+#[derive(Debug)]
+pub struct FieldInitializer {
+  pub name: usize,
+  pub value: Expression,
+}
+
 #[derive(Debug)]
 pub struct AccessExpression {
   pub receiver: Box<Expression>,
@@ -84,6 +138,39 @@ pub struct CallExpression {
   pub arguments: Vec<Expression>,
 }
 
+#[derive(Debug)]
+pub struct InvokeExpression {
+  pub receiver: Box<Expression>,
+  pub method: usize,
+  pub arguments: Vec<Expression>,
+}
+
+#[derive(Debug)]
+pub struct IndexExpression {
+  pub receiver: Box<Expression>,
+  pub index: Box<Expression>,
+}
+
+#[derive(Debug)]
+pub struct AssignExpression {
+  pub name: usize,
+  pub operand: Box<Expression>,
+}
+
+#[derive(Debug)]
+pub struct IndexAssignExpression {
+  pub receiver: Box<Expression>,
+  pub index: Box<Expression>,
+  pub operand: Box<Expression>,
+}
+
+#[derive(Debug)]
+pub struct AccessAssignExpression {
+  pub receiver: Box<Expression>,
+  pub field: usize,
+  pub operand: Box<Expression>,
+}
+
 #[derive(Debug)]
 pub struct UnaryExpression {
   pub operator: UnaryOperator,
@@ -94,6 +181,7 @@ pub struct UnaryExpression {
 pub enum UnaryOperator {
   Negate,
   Not,
+  BitNot,
 }
 
 #[derive(Debug)]
@@ -112,10 +200,17 @@ pub enum BinaryOperator {
   Less,
   LessEqual,
   Equal,
+  NotEqual,
   Add,
   Subtract,
   Multiply,
   Divide,
+  Remainder,
+  BitAnd,
+  BitOr,
+  BitXor,
+  ShiftLeft,
+  ShiftRight,
 }
 
 #[derive(Debug)]
@@ -137,6 +232,24 @@ pub struct WhileExpression {
   pub body: Block,
 }
 
+#[derive(Debug)]
+pub struct TryExpression {
+  pub body: Block,
+  pub name: usize,
+  pub catch_body: Block,
+}
+
+#[derive(Debug)]
+pub struct ThrowExpression {
+  pub operand: Box<Expression>,
+}
+
+#[derive(Debug)]
+pub struct FnExpression {
+  pub chunk: usize,
+  pub captures: Vec<usize>,
+}
+
 #[derive(Debug)]
 pub struct Path {
   pub components: Vec<usize>,
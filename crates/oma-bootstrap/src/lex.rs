@@ -7,6 +7,7 @@ use crate::{
 pub enum LexError {
   UnexpectedChar(u8),
   UnexpectedEof,
+  InvalidEscape(u8),
 }
 
 pub struct Lexer {
@@ -64,10 +65,12 @@ impl Lexer {
           _ => self.build(Token::Slash),
         }
       }
+      Some(b'%') => self.advance_and_build(Token::Percent),
       Some(b'>') => {
         self.advance();
         match self.peek() {
           Some(b'=') => self.advance_and_build(Token::GreaterEqual),
+          Some(b'>') => self.advance_and_build(Token::GreaterGreater),
           _ => self.build(Token::Greater),
         }
       }
@@ -75,6 +78,7 @@ impl Lexer {
         self.advance();
         match self.peek() {
           Some(b'=') => self.advance_and_build(Token::LessEqual),
+          Some(b'<') => self.advance_and_build(Token::LessLess),
           _ => self.build(Token::Less),
         }
       }
@@ -96,26 +100,25 @@ impl Lexer {
         self.advance();
         match self.peek() {
           Some(b'&') => self.advance_and_build(Token::AmpAmp),
-          Some(byte) => self.build_err(LexError::UnexpectedChar(byte)),
-          None => self.build_err(LexError::UnexpectedEof),
+          _ => self.build(Token::Amp),
         }
       }
       Some(b'|') => {
         self.advance();
         match self.peek() {
           Some(b'|') => self.advance_and_build(Token::PipePipe),
-          Some(byte) => self.build_err(LexError::UnexpectedChar(byte)),
-          None => self.build_err(LexError::UnexpectedEof),
+          _ => self.build(Token::Pipe),
         }
       }
+      Some(b'^') => self.advance_and_build(Token::Caret),
+      Some(b'~') => self.advance_and_build(Token::Tilde),
       Some(b',') => self.advance_and_build(Token::Comma),
       Some(b'.') => self.advance_and_build(Token::Period),
       Some(b':') => {
         self.advance();
         match self.peek() {
           Some(b':') => self.advance_and_build(Token::ColonColon),
-          Some(byte) => self.build_err(LexError::UnexpectedChar(byte)),
-          None => self.build_err(LexError::UnexpectedEof),
+          _ => self.build(Token::Colon),
         }
       }
       Some(b';') => self.advance_and_build(Token::Semicolon),
@@ -145,17 +148,37 @@ impl Lexer {
     self.build(Token::Comment)
   }
 
+  // Only the escapes that `string_literal` understands are accepted. A string
+  // with an invalid escape is still lexed up to its closing quote, so that the
+  // error covers the whole string.
   fn string(&mut self) -> Result<Spanned<Token>, Spanned<LexError>> {
+    let mut error = None;
     self.advance();
     loop {
       match self.peek() {
         Some(b'"') => break,
-        None => return self.advance_and_build_err(LexError::UnexpectedEof),
-        _ => self.advance(),
+        Some(b'\\') => {
+          self.advance();
+          match self.peek() {
+            Some(b'"' | b'\\' | b'n') => {}
+            Some(byte) => {
+              error.get_or_insert(LexError::InvalidEscape(byte));
+            }
+            None => return self.build_err(LexError::UnexpectedEof),
+          }
+          self.advance();
+        }
+        None => return self.build_err(LexError::UnexpectedEof),
+        _ => {
+          self.advance();
+        }
       };
     }
     self.advance();
-    self.build(Token::String)
+    match error {
+      Some(error) => self.build_err(error),
+      None => self.build(Token::String),
+    }
   }
 
   fn number(&mut self) -> Result<Spanned<Token>, Spanned<LexError>> {
@@ -196,12 +219,16 @@ impl Lexer {
       "use" => Ok(token.map(|_| Token::Use)),
       "mod" => Ok(token.map(|_| Token::Mod)),
       "fn" => Ok(token.map(|_| Token::Fn)),
+      "struct" => Ok(token.map(|_| Token::Struct)),
       "impl" => Ok(token.map(|_| Token::Impl)),
       "let" => Ok(token.map(|_| Token::Let)),
       "mut" => Ok(token.map(|_| Token::Mut)),
       "if" => Ok(token.map(|_| Token::If)),
       "else" => Ok(token.map(|_| Token::Else)),
       "while" => Ok(token.map(|_| Token::While)),
+      "try" => Ok(token.map(|_| Token::Try)),
+      "catch" => Ok(token.map(|_| Token::Catch)),
+      "throw" => Ok(token.map(|_| Token::Throw)),
       "true" => Ok(token.map(|_| Token::True)),
       "false" => Ok(token.map(|_| Token::False)),
       _ => Ok(token),
@@ -262,6 +289,24 @@ impl Lexer {
   }
 }
 
+// Strips the surrounding quotes off a string literal and replaces its escapes,
+// which the lexer has already checked.
+pub fn string_literal(literal: &str) -> String {
+  let mut string = String::new();
+  let mut chars = literal[1..literal.len() - 1].chars();
+  while let Some(char) = chars.next() {
+    match char {
+      '\\' => match chars.next() {
+        Some('n') => string.push('\n'),
+        Some(char) => string.push(char),
+        None => {}
+      },
+      char => string.push(char),
+    }
+  }
+  string
+}
+
 fn is_digit(byte: u8) -> bool {
   byte >= b'0' && byte <= b'9'
 }
@@ -283,7 +328,7 @@ mod tests {
     token::Token,
   };
 
-  use super::Lexer;
+  use super::{string_literal, LexError, Lexer};
 
   #[test]
   fn int() {
@@ -385,6 +430,55 @@ mod tests {
     );
   }
 
+  #[test]
+  fn bitwise() {
+    let lexer = Lexer::new("% & | ^ ~ << >>");
+    let source = lexer.source().clone();
+    let tokens = lexer.collect();
+
+    assert_eq!(
+      tokens,
+      vec![
+        Ok(Spanned::new(
+          Token::Percent,
+          Span::new(source.clone(), 0, 1)
+        )),
+        Ok(Spanned::new(Token::Amp, Span::new(source.clone(), 2, 3))),
+        Ok(Spanned::new(Token::Pipe, Span::new(source.clone(), 4, 5))),
+        Ok(Spanned::new(Token::Caret, Span::new(source.clone(), 6, 7))),
+        Ok(Spanned::new(Token::Tilde, Span::new(source.clone(), 8, 9))),
+        Ok(Spanned::new(
+          Token::LessLess,
+          Span::new(source.clone(), 10, 12)
+        )),
+        Ok(Spanned::new(
+          Token::GreaterGreater,
+          Span::new(source.clone(), 13, 15)
+        )),
+        Ok(Spanned::new(Token::Eof, Span::new(source.clone(), 15, 15))),
+      ]
+    );
+  }
+
+  #[test]
+  fn colon() {
+    let lexer = Lexer::new(": ::");
+    let source = lexer.source().clone();
+    let tokens = lexer.collect();
+
+    assert_eq!(
+      tokens,
+      vec![
+        Ok(Spanned::new(Token::Colon, Span::new(source.clone(), 0, 1))),
+        Ok(Spanned::new(
+          Token::ColonColon,
+          Span::new(source.clone(), 2, 4)
+        )),
+        Ok(Spanned::new(Token::Eof, Span::new(source.clone(), 4, 4))),
+      ]
+    );
+  }
+
   #[test]
   fn string() {
     let lexer = Lexer::new("\"foo\"");
@@ -400,9 +494,39 @@ mod tests {
     );
   }
 
+  #[test]
+  fn escapes() {
+    let lexer = Lexer::new(r#""a\"b\\c\n" "\t" "d"#);
+    let source = lexer.source().clone();
+    let tokens = lexer.collect();
+
+    assert_eq!(
+      tokens,
+      vec![
+        Ok(Spanned::new(
+          Token::String,
+          Span::new(source.clone(), 0, 11)
+        )),
+        Err(Spanned::new(
+          LexError::InvalidEscape(b't'),
+          Span::new(source.clone(), 12, 16)
+        )),
+        Err(Spanned::new(
+          LexError::UnexpectedEof,
+          Span::new(source.clone(), 17, 19)
+        )),
+        Ok(Spanned::new(Token::Eof, Span::new(source.clone(), 19, 19))),
+      ]
+    );
+
+    assert_eq!(string_literal(r#""a\"b\\c\n""#), "a\"b\\c\n");
+  }
+
   #[test]
   fn keywords() {
-    let lexer = Lexer::new("true false fn mod impl let mut if else while");
+    let lexer = Lexer::new(
+      "true false fn mod impl let mut if else while struct try catch throw",
+    );
     let source = lexer.source().clone();
     let tokens = lexer.collect();
 
@@ -418,8 +542,24 @@ mod tests {
         Ok(Spanned::new(Token::Mut, Span::new(source.clone(), 27, 30))),
         Ok(Spanned::new(Token::If, Span::new(source.clone(), 31, 33))),
         Ok(Spanned::new(Token::Else, Span::new(source.clone(), 34, 38))),
-        Ok(Spanned::new(Token::Else, Span::new(source.clone(), 39, 44))),
-        Ok(Spanned::new(Token::Eof, Span::new(source.clone(), 44, 44))),
+        Ok(Spanned::new(
+          Token::While,
+          Span::new(source.clone(), 39, 44)
+        )),
+        Ok(Spanned::new(
+          Token::Struct,
+          Span::new(source.clone(), 45, 51)
+        )),
+        Ok(Spanned::new(Token::Try, Span::new(source.clone(), 52, 55))),
+        Ok(Spanned::new(
+          Token::Catch,
+          Span::new(source.clone(), 56, 61)
+        )),
+        Ok(Spanned::new(
+          Token::Throw,
+          Span::new(source.clone(), 62, 67)
+        )),
+        Ok(Spanned::new(Token::Eof, Span::new(source.clone(), 67, 67))),
       ]
     );
   }
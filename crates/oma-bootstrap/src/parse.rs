@@ -1,9 +1,15 @@
+use std::mem;
+
 use crate::{
   ast::{
-    AccessExpression, BinaryExpression, BindStatement, Block, CallExpression,
-    Declaration, ElseBody, Expression, ExpressionStatement, File,
-    FnDeclaration, IfExpression, ModDeclaration, Path, Pattern, Statement,
-    UnaryExpression, UseDeclaration, UseTree, UseTreeBranch, WhileExpression,
+    AccessAssignExpression, AccessExpression, AssignExpression,
+    BinaryExpression, BindStatement, Block, CallExpression, Declaration,
+    ElseBody, Expression, ExpressionStatement, FieldInitializer, File,
+    FnDeclaration, FnExpression, IfExpression, ImplDeclaration,
+    IndexAssignExpression, IndexExpression, ListExpression, MapEntry,
+    MapExpression, ModDeclaration, Path, Pattern, Statement, StructDeclaration,
+    StructExpression, ThrowExpression, TryExpression, UnaryExpression,
+    UseDeclaration, UseTree, UseTreeBranch, WhileExpression,
   },
   lex::{LexError, Lexer},
   span::{Source, Spanned},
@@ -19,6 +25,9 @@ pub enum ParseError {
 pub struct Parser {
   lexer: Lexer,
   current: Option<Spanned<Token>>,
+  // Struct expressions are not allowed directly within the condition of an
+  // `if` or `while`, where the opening brace begins the body instead.
+  is_in_condition: bool,
 }
 
 macro_rules! binary {
@@ -47,6 +56,7 @@ impl Parser {
     Parser {
       lexer: Lexer::new(source),
       current: None,
+      is_in_condition: false,
     }
   }
 
@@ -111,6 +121,14 @@ impl Parser {
         .fn_declaration()
         .map(Declaration::Fn)
         .map_err(|error| vec![error]),
+      Token::Struct => self
+        .struct_declaration()
+        .map(Declaration::Struct)
+        .map_err(|error| vec![error]),
+      Token::Impl => self
+        .impl_declaration()
+        .map(Declaration::Impl)
+        .map_err(|error| vec![error]),
       token => {
         return Err(vec![self
           .advance()
@@ -227,7 +245,65 @@ impl Parser {
 
     let name = self.expect(Token::Identifier)?;
 
+    let parameters = self.parameters()?;
+
+    let body = self.block()?;
+
+    Ok(FnDeclaration {
+      name,
+      parameters,
+      body,
+    })
+  }
+
+  fn struct_declaration(
+    &mut self,
+  ) -> Result<StructDeclaration, Spanned<ParseError>> {
+    self.expect(Token::Struct)?;
+
+    let name = self.expect(Token::Identifier)?;
+
+    self.expect(Token::OpenBrace)?;
+    let mut fields = Vec::new();
+    loop {
+      if let Token::CloseBrace = self.peek()?.base() {
+        break;
+      }
+
+      fields.push(self.expect(Token::Identifier)?);
+
+      if let Token::Comma = self.peek()?.base() {
+        self.advance()?;
+      }
+    }
+    self.expect(Token::CloseBrace)?;
+
+    Ok(StructDeclaration { name, fields })
+  }
+
+  fn impl_declaration(
+    &mut self,
+  ) -> Result<ImplDeclaration, Spanned<ParseError>> {
+    self.expect(Token::Impl)?;
+
+    let name = self.expect(Token::Identifier)?;
+
+    self.expect(Token::OpenBrace)?;
+    let mut fn_declarations = Vec::new();
+    while let Token::Fn = self.peek()?.base() {
+      fn_declarations.push(self.fn_declaration()?);
+    }
+    self.expect(Token::CloseBrace)?;
+
+    Ok(ImplDeclaration {
+      name,
+      fn_declarations,
+    })
+  }
+
+  fn parameters(&mut self) -> Result<Vec<Spanned<Token>>, Spanned<ParseError>> {
     self.expect(Token::OpenParen)?;
+
     let mut parameters = Vec::new();
     loop {
       if let Token::CloseParen = self.peek()?.base() {
@@ -241,18 +317,20 @@ impl Parser {
         self.advance()?;
       }
     }
-    self.expect(Token::CloseParen)?;
 
-    let body = self.block()?;
+    self.expect(Token::CloseParen)?;
 
-    Ok(FnDeclaration {
-      name,
-      parameters,
-      body,
-    })
+    Ok(parameters)
   }
 
   fn block(&mut self) -> Result<Block, Spanned<ParseError>> {
+    let is_in_condition = mem::replace(&mut self.is_in_condition, false);
+    let block = self.block_inner();
+    self.is_in_condition = is_in_condition;
+    block
+  }
+
+  fn block_inner(&mut self) -> Result<Block, Spanned<ParseError>> {
     self.expect(Token::OpenBrace)?;
 
     let mut statements = Vec::new();
@@ -278,7 +356,7 @@ impl Parser {
   }
 
   fn bind_statement(&mut self) -> Result<Statement, Spanned<ParseError>> {
-    self.expect(Token::Let)?;
+    let keyword = self.expect(Token::Let)?;
 
     let is_mut = match self.peek()?.base() {
       Token::Mut => {
@@ -297,6 +375,7 @@ impl Parser {
     self.expect(Token::Semicolon)?;
 
     Ok(Statement::Bind(BindStatement {
+      keyword,
       is_mut,
       pattern,
       expression,
@@ -322,27 +401,87 @@ impl Parser {
     let expression = match self.peek()?.base() {
       Token::If => Expression::If(self.if_expression()?),
       Token::While => Expression::While(self.while_expression()?),
-      _ => self.logical_expression()?,
+      Token::Try => Expression::Try(self.try_expression()?),
+      Token::Throw => Expression::Throw(self.throw_expression()?),
+      Token::Fn => Expression::Fn(self.fn_expression()?),
+      _ => self.assign_expression()?,
     };
     Ok(expression)
   }
 
+  fn assign_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
+    let target = self.logical_expression()?;
+
+    let equal = match self.peek()?.base() {
+      Token::Equal => self.advance()?,
+      _ => return Ok(target),
+    };
+
+    let operand = Box::new(self.expression()?);
+
+    match target {
+      Expression::Literal(token) if token.base() == &Token::Identifier => {
+        Ok(Expression::Assign(AssignExpression {
+          pattern: Pattern::Literal(token),
+          operand,
+        }))
+      }
+      Expression::Index(index_expression) => {
+        Ok(Expression::IndexAssign(IndexAssignExpression {
+          receiver: index_expression.receiver,
+          index: index_expression.index,
+          operand,
+        }))
+      }
+      Expression::Access(access_expression) => {
+        Ok(Expression::AccessAssign(AccessAssignExpression {
+          receiver: access_expression.receiver,
+          field: access_expression.field,
+          operand,
+        }))
+      }
+      _ => Err(equal.map(ParseError::UnexpectedToken)),
+    }
+  }
+
+  fn fn_expression(&mut self) -> Result<FnExpression, Spanned<ParseError>> {
+    let keyword = self.expect(Token::Fn)?;
+
+    let parameters = self.parameters()?;
+
+    let body = self.block()?;
+
+    Ok(FnExpression {
+      keyword,
+      parameters,
+      body,
+    })
+  }
+
   fn if_expression(&mut self) -> Result<IfExpression, Spanned<ParseError>> {
-    self.expect(Token::If)?;
+    let keyword = self.expect(Token::If)?;
 
-    let condition = self.expression()?;
+    let condition = self.condition()?;
 
     let body = self.block()?;
 
     let else_body = self.else_body()?;
 
     Ok(IfExpression {
+      keyword,
       condition: Box::new(condition),
       body,
       else_body,
     })
   }
 
+  fn condition(&mut self) -> Result<Expression, Spanned<ParseError>> {
+    let is_in_condition = mem::replace(&mut self.is_in_condition, true);
+    let condition = self.expression();
+    self.is_in_condition = is_in_condition;
+    condition
+  }
+
   fn else_body(&mut self) -> Result<Option<ElseBody>, Spanned<ParseError>> {
     match self.peek()?.base() {
       Token::Else => self.advance()?,
@@ -361,17 +500,48 @@ impl Parser {
   fn while_expression(
     &mut self,
   ) -> Result<WhileExpression, Spanned<ParseError>> {
-    self.expect(Token::While)?;
+    let keyword = self.expect(Token::While)?;
 
-    let condition = self.expression()?;
+    let condition = self.condition()?;
     let body = self.block()?;
 
     Ok(WhileExpression {
+      keyword,
       condition: Box::new(condition),
       body,
     })
   }
 
+  fn try_expression(&mut self) -> Result<TryExpression, Spanned<ParseError>> {
+    let keyword = self.expect(Token::Try)?;
+
+    let body = self.block()?;
+
+    self.expect(Token::Catch)?;
+    let name = self.expect(Token::Identifier)?;
+    let catch_body = self.block()?;
+
+    Ok(TryExpression {
+      keyword,
+      body,
+      name,
+      catch_body,
+    })
+  }
+
+  fn throw_expression(
+    &mut self,
+  ) -> Result<ThrowExpression, Spanned<ParseError>> {
+    let keyword = self.expect(Token::Throw)?;
+
+    let operand = self.expression()?;
+
+    Ok(ThrowExpression {
+      keyword,
+      operand: Box::new(operand),
+    })
+  }
+
   fn logical_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
     binary! {
       self,
@@ -384,7 +554,7 @@ impl Parser {
     let left_operand = Box::new(self.comparison_expression()?);
 
     let operator = match self.peek()?.base() {
-      Token::EqualEqual => self.advance()?,
+      Token::EqualEqual | Token::BangEqual => self.advance()?,
       _ => return Ok(*left_operand),
     };
 
@@ -398,7 +568,7 @@ impl Parser {
 
     let token = self.peek()?;
     match token.base() {
-      Token::EqualEqual => {
+      Token::EqualEqual | Token::BangEqual => {
         let span = token.span().clone();
         return Err(Spanned::new(
           ParseError::UnexpectedToken(token.unwrap()),
@@ -414,7 +584,7 @@ impl Parser {
   fn comparison_expression(
     &mut self,
   ) -> Result<Expression, Spanned<ParseError>> {
-    let left_operand = Box::new(self.addition_expression()?);
+    let left_operand = Box::new(self.bit_or_expression()?);
 
     let operator = match self.peek()?.base() {
       Token::Greater | Token::GreaterEqual | Token::Less | Token::LessEqual => {
@@ -423,7 +593,7 @@ impl Parser {
       _ => return Ok(*left_operand),
     };
 
-    let right_operand = Box::new(self.addition_expression()?);
+    let right_operand = Box::new(self.bit_or_expression()?);
 
     let expression = Expression::Binary(BinaryExpression {
       left_operand,
@@ -446,6 +616,38 @@ impl Parser {
     Ok(expression)
   }
 
+  fn bit_or_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
+    binary! {
+      self,
+      bit_xor_expression,
+      Token::Pipe,
+    }
+  }
+
+  fn bit_xor_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
+    binary! {
+      self,
+      bit_and_expression,
+      Token::Caret,
+    }
+  }
+
+  fn bit_and_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
+    binary! {
+      self,
+      shift_expression,
+      Token::Amp,
+    }
+  }
+
+  fn shift_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
+    binary! {
+      self,
+      addition_expression,
+      Token::LessLess | Token::GreaterGreater,
+    }
+  }
+
   fn addition_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
     binary! {
       self,
@@ -460,20 +662,23 @@ impl Parser {
     binary! {
       self,
       unary_expression,
-      Token::Star | Token::Slash,
+      Token::Star | Token::Slash | Token::Percent,
     }
   }
 
   fn unary_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
     match self.peek()?.base() {
-      Token::Dash | Token::Bang => {
+      Token::Dash | Token::Bang | Token::Tilde => {
         let operator = self.advance()?;
         let operand = Box::new(self.unary_expression()?);
         Ok(Expression::Unary(UnaryExpression { operator, operand }))
       }
       Token::OpenParen => {
         self.advance()?;
-        let expression = self.expression()?;
+        let is_in_condition = mem::replace(&mut self.is_in_condition, false);
+        let expression = self.expression();
+        self.is_in_condition = is_in_condition;
+        let expression = expression?;
         self.expect(Token::CloseParen)?;
         Ok(expression)
       }
@@ -482,11 +687,29 @@ impl Parser {
   }
 
   fn call_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
-    let mut receiver = Box::new(self.access_expression()?);
+    let mut receiver = Box::new(self.primary_expression()?);
 
     loop {
       match self.peek()?.base() {
         Token::OpenParen => self.advance()?,
+        Token::OpenBracket => {
+          self.advance()?;
+          let index = Box::new(self.expression()?);
+          self.expect(Token::CloseBracket)?;
+          receiver =
+            Box::new(Expression::Index(IndexExpression { receiver, index }));
+          continue;
+        }
+        Token::Period => {
+          self.advance()?;
+          let field = match self.peek()?.base() {
+            Token::Identifier | Token::Int => self.advance()?,
+            _ => return Err(self.advance()?.map(ParseError::UnexpectedToken)),
+          };
+          receiver =
+            Box::new(Expression::Access(AccessExpression { receiver, field }));
+          continue;
+        }
         _ => return Ok(*receiver),
       };
 
@@ -509,23 +732,98 @@ impl Parser {
     }
   }
 
-  fn access_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
-    let mut receiver = Box::new(self.path_expression()?);
+  fn primary_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
+    match self.peek()?.base() {
+      Token::OpenBracket => self.collection_expression(),
+      _ => self.path_expression(),
+    }
+  }
+
+  // Parses either a list or a map, which are told apart by whether the first
+  // element is followed by a colon. An empty map is written as `[:]`.
+  fn collection_expression(
+    &mut self,
+  ) -> Result<Expression, Spanned<ParseError>> {
+    let bracket = self.expect(Token::OpenBracket)?;
+
+    match self.peek()?.base() {
+      Token::CloseBracket => {
+        self.advance()?;
+        return Ok(Expression::List(ListExpression {
+          bracket,
+          elements: Vec::new(),
+        }));
+      }
+      Token::Colon => {
+        self.advance()?;
+        self.expect(Token::CloseBracket)?;
+        return Ok(Expression::Map(MapExpression {
+          bracket,
+          entries: Vec::new(),
+        }));
+      }
+      _ => {}
+    }
+
+    let first = self.expression()?;
+
+    if let Token::Colon = self.peek()?.base() {
+      self.advance()?;
+      let value = self.expression()?;
+      let entry = MapEntry { key: first, value };
+      Ok(Expression::Map(self.map_expression(bracket, entry)?))
+    } else {
+      Ok(Expression::List(self.list_expression(bracket, first)?))
+    }
+  }
 
+  fn list_expression(
+    &mut self,
+    bracket: Spanned<Token>,
+    first: Expression,
+  ) -> Result<ListExpression, Spanned<ParseError>> {
+    let mut elements = vec![first];
     loop {
-      match self.peek()?.base() {
-        Token::Period => self.advance()?,
-        _ => return Ok(*receiver),
-      };
+      if let Token::Comma = self.peek()?.base() {
+        self.advance()?;
+      }
 
-      let field = match self.peek()?.base() {
-        Token::Identifier | Token::Int => self.advance()?,
-        _ => return Err(self.advance()?.map(ParseError::UnexpectedToken)),
-      };
+      if let Token::CloseBracket = self.peek()?.base() {
+        break;
+      }
 
-      receiver =
-        Box::new(Expression::Access(AccessExpression { receiver, field }));
+      elements.push(self.expression()?);
     }
+
+    self.expect(Token::CloseBracket)?;
+
+    Ok(ListExpression { bracket, elements })
+  }
+
+  fn map_expression(
+    &mut self,
+    bracket: Spanned<Token>,
+    first: MapEntry,
+  ) -> Result<MapExpression, Spanned<ParseError>> {
+    let mut entries = vec![first];
+    loop {
+      if let Token::Comma = self.peek()?.base() {
+        self.advance()?;
+      }
+
+      if let Token::CloseBracket = self.peek()?.base() {
+        break;
+      }
+
+      let key = self.expression()?;
+      self.expect(Token::Colon)?;
+      let value = self.expression()?;
+      entries.push(MapEntry { key, value });
+    }
+
+    self.expect(Token::CloseBracket)?;
+
+    Ok(MapExpression { bracket, entries })
   }
 
   fn path_expression(&mut self) -> Result<Expression, Spanned<ParseError>> {
@@ -533,6 +831,9 @@ impl Parser {
 
     match (token.base(), self.peek()?.base()) {
       (Token::Identifier, Token::ColonColon) => self.advance()?,
+      (Token::Identifier, Token::OpenBrace) if !self.is_in_condition => {
+        return Ok(Expression::Struct(self.struct_expression(token)?));
+      }
       _ => return Ok(Expression::Literal(token)),
     };
 
@@ -549,12 +850,40 @@ impl Parser {
     Ok(Expression::Path(Path { components }))
   }
 
+  fn struct_expression(
+    &mut self,
+    name: Spanned<Token>,
+  ) -> Result<StructExpression, Spanned<ParseError>> {
+    self.expect(Token::OpenBrace)?;
+
+    let mut fields = Vec::new();
+    loop {
+      if let Token::CloseBrace = self.peek()?.base() {
+        break;
+      }
+
+      let name = self.expect(Token::Identifier)?;
+      self.expect(Token::Colon)?;
+      let value = self.expression()?;
+      fields.push(FieldInitializer { name, value });
+
+      if let Token::Comma = self.peek()?.base() {
+        self.advance()?;
+      }
+    }
+
+    self.expect(Token::CloseBrace)?;
+
+    Ok(StructExpression { name, fields })
+  }
+
   fn literal_expression(
     &mut self,
   ) -> Result<Spanned<Token>, Spanned<ParseError>> {
     match self.peek()?.base() {
       Token::Int
       | Token::Float
+      | Token::String
       | Token::True
       | Token::False
       | Token::Identifier => Ok(self.advance()?),
@@ -579,8 +908,9 @@ impl Parser {
 
   fn synchronize(&mut self) {
     loop {
-      if let Ok(Token::Fn | Token::Mod | Token::Eof) =
-        self.peek().map(|token| token.base().clone())
+      if let Ok(
+        Token::Fn | Token::Struct | Token::Impl | Token::Mod | Token::Eof,
+      ) = self.peek().map(|token| token.base().clone())
       {
         return;
       }
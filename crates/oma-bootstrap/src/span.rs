@@ -1,6 +1,6 @@
 use std::{
   cmp::{max, min},
-  fmt,
+  fmt, iter,
   rc::Rc,
 };
 
@@ -89,6 +89,11 @@ impl Span {
   pub fn to_string(&self) -> String {
     self.as_str().to_string()
   }
+
+  // The line and column the span starts at, both counting from 1.
+  pub fn line_column(&self) -> (usize, usize) {
+    self.source.line_column(self.start)
+  }
 }
 
 impl fmt::Debug for Span {
@@ -101,15 +106,28 @@ impl fmt::Debug for Span {
 pub struct Source {
   // TODO: Replace with interned string
   inner: Rc<String>,
+  // The offset of the first byte of each line.
+  lines: Rc<Vec<usize>>,
 }
 
 impl Source {
   pub fn new(source: &str) -> Source {
+    let lines = iter::once(0)
+      .chain(source.match_indices('\n').map(|(index, _)| index + 1))
+      .collect();
     Source {
       inner: Rc::new(source.to_string()),
+      lines: Rc::new(lines),
     }
   }
 
+  // Columns are counted in characters rather than bytes.
+  pub fn line_column(&self, index: usize) -> (usize, usize) {
+    let line = self.lines.partition_point(|&start| start <= index) - 1;
+    let column = self.slice(self.lines[line], index).chars().count();
+    (line + 1, column + 1)
+  }
+
   // TODO: Replace with std::ops::Index implementation
   pub fn slice(&self, start: usize, end: usize) -> &str {
     &self.inner.as_str()[start..end]
@@ -7,18 +7,26 @@ pub enum Token {
   Dash,
   Star,
   Slash,
+  Percent,
   Greater,
   GreaterEqual,
+  GreaterGreater,
   Less,
   LessEqual,
+  LessLess,
   Equal,
   EqualEqual,
   Bang,
   BangEqual,
+  Amp,
   AmpAmp,
+  Pipe,
   PipePipe,
+  Caret,
+  Tilde,
   Comma,
   Period,
+  Colon,
   ColonColon,
   Semicolon,
   OpenParen,
@@ -31,12 +39,16 @@ pub enum Token {
   Use,
   Mod,
   Fn,
+  Struct,
   Impl,
   Let,
   Mut,
   If,
   Else,
   While,
+  Try,
+  Catch,
+  Throw,
   True,
   False,
   Comment,
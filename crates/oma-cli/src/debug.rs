@@ -0,0 +1,301 @@
+use std::io::{self, BufRead, Write};
+
+use oma::{
+  executable::Executable,
+  machine::{Machine, Status, Trace},
+};
+
+const HELP: &str = "\
+commands:
+  step, s [count]             execute the next instruction(s)
+  continue, c                 execute until a breakpoint or the end
+  break, b <chunk> <offset>   set a breakpoint
+  break, b <line>             set breakpoints at a source line
+  delete, d <chunk> <offset>  remove a breakpoint
+  stack                       print the value stack
+  frames, bt                  print the call frames
+  list, l                     disassemble the current chunk
+  run, r                      restart execution
+  help, h                     print this message
+  quit, q                     exit the debugger";
+
+pub fn debug(path: &str) {
+  let executable = match crate::load(path) {
+    Some(executable) => executable,
+    None => return,
+  };
+
+  let mut machine = Machine::new();
+  if let Err(error) = machine.start(&executable) {
+    eprintln!("error: {:?}", error);
+    return;
+  }
+  println!("{}", HELP);
+  location(&machine, &executable);
+
+  let stdin = io::stdin();
+  let mut lines = stdin.lock().lines();
+  loop {
+    print!("(oma) ");
+    io::stdout().flush().expect("failed to flush stdout");
+
+    let line = match lines.next() {
+      Some(Ok(line)) => line,
+      _ => break,
+    };
+    let arguments: Vec<&str> = line.split_whitespace().collect();
+
+    match arguments.as_slice() {
+      [] => {}
+      ["step" | "s"] => step(&mut machine, &executable, 1),
+      ["step" | "s", count] => match parse_number(count) {
+        Some(count) => step(&mut machine, &executable, count),
+        None => println!("invalid count: {}", count),
+      },
+      ["continue" | "c"] => {
+        let status = machine.resume(&executable);
+        report(&machine, &executable, status);
+      }
+      ["break" | "b", line] => match parse_number(line) {
+        Some(line) => {
+          let breakpoints = line_breakpoints(&executable, line);
+          if breakpoints.is_empty() {
+            println!("no code at line {}", line);
+          }
+          for (chunk, offset) in breakpoints {
+            machine.add_breakpoint(chunk, offset);
+          }
+        }
+        None => println!("invalid line: {}", line),
+      },
+      ["break" | "b", chunk, offset] => {
+        match (parse_number(chunk), parse_number(offset)) {
+          (Some(chunk), Some(offset)) => machine.add_breakpoint(chunk, offset),
+          _ => println!("invalid breakpoint: {} {}", chunk, offset),
+        }
+      }
+      ["delete" | "d", chunk, offset] => {
+        match (parse_number(chunk), parse_number(offset)) {
+          (Some(chunk), Some(offset)) => {
+            if !machine.remove_breakpoint(chunk, offset) {
+              println!("no breakpoint at {} {}", chunk, offset);
+            }
+          }
+          _ => println!("invalid breakpoint: {} {}", chunk, offset),
+        }
+      }
+      ["stack"] => {
+        for (index, value) in machine.stack().iter().enumerate() {
+          println!("{:#06x} {}", index, value.display(machine.heap()));
+        }
+      }
+      ["frames" | "bt"] => {
+        for frame in machine.frames() {
+          println!(
+            "chunk {:#010x} offset {:#010x} base {:#06x}",
+            frame.chunk(),
+            frame.offset(),
+            frame.base()
+          );
+        }
+      }
+      ["list" | "l"] => {
+        if let Some(frame) = machine.frames().last() {
+          if let Some(chunk) = executable.chunk(frame.chunk()) {
+            println!("{}", chunk);
+          }
+        }
+      }
+      ["run" | "r"] => match machine.start(&executable) {
+        Ok(()) => location(&machine, &executable),
+        Err(error) => println!("error: {:?}", error),
+      },
+      ["help" | "h"] => println!("{}", HELP),
+      ["quit" | "q"] => break,
+      _ => println!("unknown command: {}", line.trim()),
+    }
+  }
+}
+
+fn step(machine: &mut Machine, executable: &Executable, count: usize) {
+  for _ in 0..count {
+    let status = machine.step(executable);
+    let is_running = matches!(status, Ok(Status::Running));
+    if !is_running || count == 1 {
+      report(machine, executable, status);
+    }
+    if !is_running {
+      return;
+    }
+  }
+  if count > 1 {
+    location(machine, executable);
+  }
+}
+
+fn report(
+  machine: &Machine,
+  executable: &Executable,
+  status: Result<Status, Trace>,
+) {
+  match status {
+    Ok(Status::Finished(result)) => {
+      println!("finished: {}", result.display(machine.heap()))
+    }
+    Ok(_) => location(machine, executable),
+    Err(trace) => {
+      print!(
+        "{}",
+        crate::render_trace(executable, machine.heap(), &trace)
+      )
+    }
+  }
+}
+
+// Prints the next instruction to be executed, as it appears in the chunk's
+// disassembly.
+fn location(machine: &Machine, executable: &Executable) {
+  let frame = match machine.frames().last() {
+    Some(frame) => frame,
+    None => return,
+  };
+  let chunk = match executable.chunk(frame.chunk()) {
+    Some(chunk) => chunk,
+    None => return,
+  };
+  let disassembly = chunk.to_string();
+  let offset = format!("{:#010x}", frame.offset());
+  let instruction = disassembly
+    .lines()
+    .find(|line| line.starts_with(&offset))
+    .unwrap_or(&offset);
+  match chunk.location(frame.offset()) {
+    Some(location) => println!(
+      "chunk {:#010x} {} ({}:{})",
+      frame.chunk(),
+      instruction,
+      executable.file(),
+      location
+    ),
+    None => println!("chunk {:#010x} {}", frame.chunk(), instruction),
+  }
+}
+
+// Returns the first offset of each chunk with a location on the line, which is
+// the start of the first statement on it.
+fn line_breakpoints(
+  executable: &Executable,
+  line: usize,
+) -> Vec<(usize, usize)> {
+  executable
+    .chunks()
+    .iter()
+    .enumerate()
+    .filter_map(|(index, chunk)| {
+      chunk
+        .locations()
+        .iter()
+        .find(|(_, location)| location.line == line)
+        .map(|&(offset, _)| (index, offset))
+    })
+    .collect()
+}
+
+fn parse_number(string: &str) -> Option<usize> {
+  match string.strip_prefix("0x") {
+    Some(hex) => usize::from_str_radix(hex, 16).ok(),
+    None => string.parse().ok(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use oma::{
+    executable::{Chunk, Executable, Location},
+    instruction::Instruction,
+    machine::{Machine, Status},
+    value::Value,
+  };
+  use oma_bootstrap::{gen::Generator, parse::Parser};
+
+  use super::line_breakpoints;
+
+  fn generate(source: &str) -> Executable {
+    let file = Parser::new(source).parse().expect("failed to parse");
+    Generator::new().generate(file).expect("failed to generate")
+  }
+
+  // Resumes the machine to the next breakpoint, and returns the location it
+  // stopped at.
+  fn resume(machine: &mut Machine, executable: &Executable) -> Location {
+    assert!(matches!(machine.resume(executable), Ok(Status::Breakpoint)));
+    let frame = machine.frames().last().unwrap();
+    let chunk = executable.chunk(frame.chunk()).unwrap();
+    chunk.location(frame.offset()).unwrap()
+  }
+
+  #[test]
+  fn line() {
+    let mut executable = Executable::new();
+    for lines in [[1, 2, 2], [3, 2, 4]] {
+      let mut chunk = Chunk::new();
+      for line in lines {
+        chunk.add_location(Location { line, column: 1 });
+        chunk.emit(Instruction::PushUnit);
+      }
+      executable.add_chunk(chunk);
+    }
+
+    assert_eq!(line_breakpoints(&executable, 2), vec![(0, 1), (1, 1)]);
+    assert_eq!(line_breakpoints(&executable, 4), vec![(1, 2)]);
+    assert_eq!(line_breakpoints(&executable, 5), vec![]);
+  }
+
+  #[test]
+  fn bind() {
+    let executable =
+      generate("fn main() {\n  let x = 1;\n  let y = x + 1;\n  y\n}");
+    let mut machine = Machine::new();
+    machine.start(&executable).unwrap();
+    let breakpoints = line_breakpoints(&executable, 3);
+    assert_eq!(breakpoints.len(), 1);
+    for (chunk, offset) in breakpoints {
+      machine.add_breakpoint(chunk, offset);
+    }
+
+    // The bind is broken at before its expression is evaluated.
+    assert_eq!(
+      resume(&mut machine, &executable),
+      Location { line: 3, column: 3 }
+    );
+    assert_eq!(machine.stack().last(), Some(&Value::Int(1)));
+    assert!(matches!(
+      machine.resume(&executable),
+      Ok(Status::Finished(Value::Int(2)))
+    ));
+  }
+
+  #[test]
+  fn statements() {
+    let executable =
+      generate("fn main() {\n  let x = 1;\n  let y = x + 1; let z = y; z\n}");
+    let mut machine = Machine::new();
+    machine.start(&executable).unwrap();
+    let breakpoints = line_breakpoints(&executable, 3);
+    assert_eq!(breakpoints.len(), 1);
+    for (chunk, offset) in breakpoints {
+      machine.add_breakpoint(chunk, offset);
+    }
+
+    // Only the first statement on the line is broken at.
+    assert_eq!(
+      resume(&mut machine, &executable),
+      Location { line: 3, column: 3 }
+    );
+    assert_eq!(machine.stack().last(), Some(&Value::Int(1)));
+    assert!(matches!(
+      machine.resume(&executable),
+      Ok(Status::Finished(Value::Int(2)))
+    ));
+  }
+}
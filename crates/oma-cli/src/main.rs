@@ -1,8 +1,27 @@
-use std::fs;
+mod debug;
 
-use oma_bootstrap::{compile::Compiler, parse::Parser};
+use std::{env, fs, io::Cursor, path::Path};
+
+use oma::{
+  assembly,
+  executable::Executable,
+  heap::Heap,
+  machine::{Error, Machine, Trace},
+};
+use oma_bootstrap::{compile::Compiler, gen::Generator, parse::Parser};
 
 fn main() {
+  let arguments: Vec<String> = env::args().collect();
+  if let [_, command, path] = arguments.as_slice() {
+    match command.as_str() {
+      "debug" => return debug::debug(path),
+      "build" => return build(path),
+      "disassemble" => return disassemble(path),
+      "run" => return run(path),
+      _ => {}
+    }
+  }
+
   let compiler = Compiler::new();
 
   // let generator = Generator::new();
@@ -17,3 +36,128 @@ fn main() {
   // let result = machine.execute(&chunk).expect("execution error");
   // println!("{}", result);
 }
+
+// Compiles the source file into an executable next to it, which `run` and
+// `debug` can load without compiling the source again.
+fn build(path: &str) {
+  let executable = match load(path) {
+    Some(executable) => executable,
+    None => return,
+  };
+  let output = Path::new(path).with_extension("omac");
+  if let Err(error) = fs::write(&output, executable.to_bytes()) {
+    eprintln!("failed to write {}: {}", output.display(), error);
+  }
+}
+
+// Prints the executable as `.omasm` text, which `load` assembles back into the
+// same executable.
+fn disassemble(path: &str) {
+  if let Some(executable) = load(path) {
+    print!("{}", assembly::disassemble(&executable));
+  }
+}
+
+fn run(path: &str) {
+  let executable = match load(path) {
+    Some(executable) => executable,
+    None => return,
+  };
+  let mut machine = Machine::new();
+  match machine.execute(&executable) {
+    Ok(result) => println!("{}", result.display(machine.heap())),
+    Err(trace) => {
+      eprint!("{}", render_trace(&executable, machine.heap(), &trace))
+    }
+  }
+}
+
+// Loads a compiled executable, assembles an `.omasm` file, or compiles the
+// source file otherwise. Errors are reported before returning None.
+pub fn load(path: &str) -> Option<Executable> {
+  let extension = Path::new(path).extension();
+  if extension.map_or(false, |ext| ext == "omac") {
+    let bytes = match fs::read(path) {
+      Ok(bytes) => bytes,
+      Err(error) => {
+        eprintln!("failed to read {}: {}", path, error);
+        return None;
+      }
+    };
+    return match Executable::from_bytes(&mut Cursor::new(bytes)) {
+      Ok(executable) => Some(executable),
+      Err(error) => {
+        eprintln!("failed to load {}: {:?}", path, error);
+        None
+      }
+    };
+  }
+
+  let source = match fs::read_to_string(path) {
+    Ok(source) => source,
+    Err(error) => {
+      eprintln!("failed to read {}: {}", path, error);
+      return None;
+    }
+  };
+  if extension.map_or(false, |ext| ext == "omasm") {
+    return match assembly::assemble(&source) {
+      Ok(executable) => Some(executable),
+      Err(error) => {
+        eprintln!("failed to assemble {}: {:?}", path, error);
+        None
+      }
+    };
+  }
+
+  match Parser::new(&source).parse() {
+    Ok(file) => match Generator::new().generate(file) {
+      Ok(mut executable) => {
+        executable.set_file(path.to_string());
+        Some(executable)
+      }
+      Err(error) => {
+        let (line, column) = error.span().line_column();
+        eprintln!("{}:{}:{}: {:?}", path, line, column, error);
+        None
+      }
+    },
+    Err(errors) => {
+      for error in errors {
+        eprintln!("{:?}", error);
+      }
+      None
+    }
+  }
+}
+
+// Renders the error followed by one line for each frame that was active when it
+// occurred, innermost first. Uncaught values are displayed from the heap they
+// live in.
+pub fn render_trace(
+  executable: &Executable,
+  heap: &Heap,
+  trace: &Trace,
+) -> String {
+  let mut rendered = match &trace.error {
+    Error::Uncaught(value) => {
+      format!("error: uncaught {}\n", value.display(heap))
+    }
+    error => format!("error: {:?}\n", error),
+  };
+  for frame in trace.frames.iter() {
+    let name = match frame.name.as_str() {
+      "" => "<closure>",
+      name => name,
+    };
+    rendered += &format!(
+      "  at {} (chunk {:#010x}, offset {:#010x})",
+      name, frame.chunk, frame.offset
+    );
+    if let Some(location) = frame.location {
+      rendered += &format!(" {}:{}", executable.file(), location);
+    }
+    rendered += "\n";
+  }
+  rendered
+}
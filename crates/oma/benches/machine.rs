@@ -0,0 +1,83 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use oma::{executable::Executable, machine::Machine};
+use oma_bootstrap::{gen::Generator, parse::Parser};
+
+const FIB: &str = "
+  fn main() {
+    fib(20)
+  }
+
+  fn fib(n) {
+    if n < 2 {
+      n
+    } else {
+      fib(n - 1) + fib(n - 2)
+    }
+  }
+";
+
+const COUNT: &str = "
+  fn main() {
+    let mut counter = 0;
+    while counter < 100000 {
+      counter = counter + 1;
+    }
+    counter
+  }
+";
+
+const CLOSURE: &str = "
+  fn main() {
+    let mut counter = 0;
+    let increment = fn() { counter = counter + 1; };
+    while counter < 100000 {
+      increment();
+    }
+    counter
+  }
+";
+
+const RECORD: &str = "
+  struct Counter { count }
+
+  impl Counter {
+    fn increment(self) {
+      self.count = self.count + 1
+    }
+  }
+
+  fn main() {
+    let counter = Counter { count: 0 };
+    while counter.count < 100000 {
+      counter.increment();
+    }
+    counter.count
+  }
+";
+
+fn generate(source: &str) -> Executable {
+  let file = Parser::new(source).parse().expect("failed to parse");
+  Generator::new().generate(file).expect("failed to generate")
+}
+
+fn bench(c: &mut Criterion, name: &str, source: &str) {
+  let executable = generate(source);
+  let code: usize = executable.chunks().iter().map(|c| c.code().len()).sum();
+
+  // The size of the code is part of the name, so that it is reported alongside
+  // the time.
+  let id = BenchmarkId::new(name, format!("{} bytes", code));
+  c.bench_with_input(id, &executable, |b, executable| {
+    b.iter(|| Machine::new().execute(executable).unwrap())
+  });
+}
+
+fn machine(c: &mut Criterion) {
+  bench(c, "fib", FIB);
+  bench(c, "count", COUNT);
+  bench(c, "closure", CLOSURE);
+  bench(c, "record", RECORD);
+}
+
+criterion_group!(benches, machine);
+criterion_main!(benches);
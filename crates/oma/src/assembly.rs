@@ -0,0 +1,955 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  convert::{TryFrom, TryInto},
+  fmt,
+  iter::Peekable,
+};
+
+use num_traits::FromPrimitive;
+
+use crate::{
+  executable::{
+    Capture, Chunk, Constant, Executable, Handler, Location, Struct,
+  },
+  instruction::{self, Instruction, JUMP_OPERAND_LEN},
+};
+
+// The first field of each variant is the line of the offending statement,
+// counting from 1.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+  InvalidDirective(usize, String),
+  InvalidInstruction(usize, String),
+  InvalidOperand(usize, String),
+  MissingOperand(usize),
+  UnexpectedOperand(usize, String),
+  InvalidString(usize),
+  InvalidLabel(usize, String),
+  DuplicateLabel(usize, String),
+  UndefinedLabel(usize, String),
+  UndefinedLocal(usize, String),
+  InvalidJump(usize, u64),
+  OutsideChunk(usize),
+  OutsideStruct(usize),
+}
+
+// Assembles an executable from its `.omasm` text, as written by `disassemble`.
+//
+// Each line holds a directive, a label or an instruction, and anything after a
+// `;` is a comment. The `.file`, `.entry` and `.section` directives describe
+// the executable, `.struct` starts a struct that `.field` and `.method` add to,
+// and `.chunk` starts a chunk that the following `.arity`, `.local`,
+// `.capture`, `.constant`, `.handler`, `.location` and `.byte` directives,
+// labels and instructions add to. Names are written as string literals. Jumps
+// and handlers take labels of their chunk or offsets, and PushLocal and
+// SetLocal take the name of a local or a slot. Every other operand is an
+// integer.
+pub fn assemble(source: &str) -> Result<Executable, Error> {
+  let mut assembler = Assembler::new();
+  for (index, text) in source.lines().enumerate() {
+    assembler.line(index + 1, text)?;
+  }
+  assembler.finish()
+}
+
+// Writes the executable as `.omasm` text that assembles back into an equal
+// executable. Code that the assembler would encode differently, such as jumps
+// whose targets are not padded, is written out byte by byte.
+pub fn disassemble(executable: &Executable) -> String {
+  Disassembly(executable).to_string()
+}
+
+// A chunk under assembly, along with the offsets of its labels, and the jumps
+// to patch and handlers to add once every label is known.
+struct Pending {
+  chunk: Chunk,
+  labels: HashMap<String, usize>,
+  jumps: Vec<(usize, usize, String)>,
+  handlers: Vec<(usize, [String; 3])>,
+}
+
+struct Assembler {
+  executable: Executable,
+  chunk: Option<Pending>,
+  structure: Option<Struct>,
+}
+
+impl Assembler {
+  fn new() -> Assembler {
+    Assembler {
+      executable: Executable::new(),
+      chunk: None,
+      structure: None,
+    }
+  }
+
+  fn line(&mut self, line: usize, text: &str) -> Result<(), Error> {
+    let words = words(text).ok_or(Error::InvalidString(line))?;
+    let (first, rest) = match words.split_first() {
+      Some(split) => split,
+      None => return Ok(()),
+    };
+
+    if let Some(label) = first.strip_suffix(':') {
+      let [] = arguments(line, rest)?;
+      self.label(line, label)
+    } else if let Some(directive) = first.strip_prefix('.') {
+      self.directive(line, directive, rest)
+    } else {
+      self.instruction(line, first, rest)
+    }
+  }
+
+  fn label(&mut self, line: usize, label: &str) -> Result<(), Error> {
+    if !is_identifier(label) {
+      return Err(Error::InvalidLabel(line, label.to_string()));
+    }
+    let pending = self.chunk.as_mut().ok_or(Error::OutsideChunk(line))?;
+    let offset = pending.chunk.code().len();
+    if pending.labels.insert(label.to_string(), offset).is_some() {
+      return Err(Error::DuplicateLabel(line, label.to_string()));
+    }
+    Ok(())
+  }
+
+  fn directive(
+    &mut self,
+    line: usize,
+    directive: &str,
+    words: &[&str],
+  ) -> Result<(), Error> {
+    match directive {
+      "file" => {
+        let [file] = arguments(line, words)?;
+        self.executable.set_file(string(line, file)?);
+      }
+      "entry" => {
+        let [entry] = arguments(line, words)?;
+        self.executable.set_entry(integer(line, entry)? as usize);
+      }
+      "section" => {
+        let (name, bytes) = match words {
+          [] => return Err(Error::MissingOperand(line)),
+          [name] => (name, Vec::new()),
+          [name, bytes] => (name, hex(line, bytes)?),
+          [_, _, word, ..] => {
+            return Err(Error::UnexpectedOperand(line, word.to_string()))
+          }
+        };
+        self.executable.set_section(string(line, name)?, bytes);
+      }
+      "struct" => {
+        let [name] = arguments(line, words)?;
+        self.end()?;
+        self.structure = Some(Struct {
+          name: string(line, name)?,
+          fields: Vec::new(),
+          methods: HashMap::new(),
+        });
+      }
+      "field" => {
+        let [name] = arguments(line, words)?;
+        let name = string(line, name)?;
+        self.structure(line)?.fields.push(name);
+      }
+      "method" => {
+        let [name, chunk] = arguments(line, words)?;
+        let (name, chunk) = (string(line, name)?, integer(line, chunk)?);
+        self.structure(line)?.methods.insert(name, chunk as usize);
+      }
+      "chunk" => {
+        let [name] = arguments(line, words)?;
+        self.end()?;
+        let mut chunk = Chunk::new();
+        chunk.set_name(string(line, name)?);
+        self.chunk = Some(Pending {
+          chunk,
+          labels: HashMap::new(),
+          jumps: Vec::new(),
+          handlers: Vec::new(),
+        });
+      }
+      "arity" => {
+        let [arity] = arguments(line, words)?;
+        let arity = integer(line, arity)?;
+        self.chunk(line)?.set_arity(arity as usize);
+      }
+      "local" => {
+        let [name] = arguments(line, words)?;
+        let name = string(line, name)?;
+        self.chunk(line)?.add_local(name);
+      }
+      "capture" => {
+        let [kind, index] = arguments(line, words)?;
+        let is_local = match kind {
+          "local" => true,
+          "upvalue" => false,
+          _ => return Err(Error::InvalidOperand(line, kind.to_string())),
+        };
+        let index = integer(line, index)? as usize;
+        self.chunk(line)?.add_capture(Capture { is_local, index });
+      }
+      "constant" => {
+        let [constant] = arguments(line, words)?;
+        let constant = parse_constant(constant)
+          .ok_or_else(|| Error::InvalidOperand(line, constant.to_string()))?;
+        self.chunk(line)?.add_constant(constant);
+      }
+      "location" => {
+        let [location] = arguments(line, words)?;
+        let location = parse_location(location)
+          .ok_or_else(|| Error::InvalidOperand(line, location.to_string()))?;
+        self.chunk(line)?.add_location(location);
+      }
+      "handler" => {
+        let offsets = arguments::<3>(line, words)?;
+        if let Some(word) = offsets
+          .iter()
+          .find(|word| parse_integer(word).is_none() && !is_identifier(word))
+        {
+          return Err(Error::InvalidOperand(line, word.to_string()));
+        }
+        let pending = self.chunk.as_mut().ok_or(Error::OutsideChunk(line))?;
+        let offsets = offsets.map(|word| word.to_string());
+        pending.handlers.push((line, offsets));
+      }
+      "byte" => {
+        let [byte] = arguments(line, words)?;
+        let byte = u8::try_from(integer(line, byte)?)
+          .map_err(|_| Error::InvalidOperand(line, byte.to_string()))?;
+        self.chunk(line)?.emit_byte(byte);
+      }
+      _ => return Err(Error::InvalidDirective(line, directive.to_string())),
+    }
+    Ok(())
+  }
+
+  fn instruction(
+    &mut self,
+    line: usize,
+    mnemonic: &str,
+    words: &[&str],
+  ) -> Result<(), Error> {
+    let instruction = parse_instruction(mnemonic)
+      .ok_or_else(|| Error::InvalidInstruction(line, mnemonic.to_string()))?;
+    let pending = self.chunk.as_mut().ok_or(Error::OutsideChunk(line))?;
+
+    if words.len() < instruction.operands() {
+      return Err(Error::MissingOperand(line));
+    }
+    if let Some(word) = words.get(instruction.operands()) {
+      return Err(Error::UnexpectedOperand(line, word.to_string()));
+    }
+
+    match instruction {
+      Instruction::Jump | Instruction::JumpIf => {
+        let offset = pending.chunk.emit_jump(instruction);
+        match parse_integer(words[0]) {
+          Some(target) => patch(&mut pending.chunk, line, offset, target)?,
+          None if is_identifier(words[0]) => {
+            pending.jumps.push((line, offset, words[0].to_string()))
+          }
+          None => {
+            return Err(Error::InvalidOperand(line, words[0].to_string()))
+          }
+        }
+      }
+      Instruction::PushLocal | Instruction::SetLocal => {
+        let slot = match parse_integer(words[0]) {
+          Some(slot) => slot,
+          None => pending
+            .chunk
+            .locals()
+            .iter()
+            .rposition(|local| local == words[0])
+            .ok_or_else(|| Error::UndefinedLocal(line, words[0].to_string()))?
+            as u64,
+        };
+        pending.chunk.emit(instruction);
+        pending.chunk.emit_operand(slot);
+      }
+      _ => {
+        pending.chunk.emit(instruction);
+        for word in words {
+          pending.chunk.emit_operand(integer(line, word)?);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn chunk(&mut self, line: usize) -> Result<&mut Chunk, Error> {
+    match self.chunk.as_mut() {
+      Some(pending) => Ok(&mut pending.chunk),
+      None => Err(Error::OutsideChunk(line)),
+    }
+  }
+
+  fn structure(&mut self, line: usize) -> Result<&mut Struct, Error> {
+    self.structure.as_mut().ok_or(Error::OutsideStruct(line))
+  }
+
+  // Adds the struct or chunk under assembly to the executable, patching the
+  // jumps and adding the handlers of the chunk first.
+  fn end(&mut self) -> Result<(), Error> {
+    if let Some(structure) = self.structure.take() {
+      self.executable.add_struct(structure);
+    }
+    if let Some(mut pending) = self.chunk.take() {
+      for (line, offset, label) in pending.jumps {
+        let target = *pending
+          .labels
+          .get(&label)
+          .ok_or(Error::UndefinedLabel(line, label))?;
+        patch(&mut pending.chunk, line, offset, target as u64)?;
+      }
+      for (line, [start, end, target]) in pending.handlers {
+        let labels = &pending.labels;
+        let resolve = |word: String| match parse_integer(&word) {
+          Some(offset) => Ok(offset as usize),
+          None => labels
+            .get(&word)
+            .copied()
+            .ok_or(Error::UndefinedLabel(line, word)),
+        };
+        pending.chunk.add_handler(Handler {
+          start: resolve(start)?,
+          end: resolve(end)?,
+          target: resolve(target)?,
+        });
+      }
+      self.executable.add_chunk(pending.chunk);
+    }
+    Ok(())
+  }
+
+  fn finish(mut self) -> Result<Executable, Error> {
+    self.end()?;
+    Ok(self.executable)
+  }
+}
+
+fn patch(
+  chunk: &mut Chunk,
+  line: usize,
+  offset: usize,
+  target: u64,
+) -> Result<(), Error> {
+  if instruction::encode_padded_operand::<JUMP_OPERAND_LEN>(target).is_none() {
+    return Err(Error::InvalidJump(line, target));
+  }
+  chunk.patch_jump(offset, target as usize);
+  Ok(())
+}
+
+// Returns exactly `N` arguments of a statement.
+fn arguments<'a, const N: usize>(
+  line: usize,
+  words: &[&'a str],
+) -> Result<[&'a str; N], Error> {
+  if let Some(word) = words.get(N) {
+    return Err(Error::UnexpectedOperand(line, word.to_string()));
+  }
+  words.try_into().map_err(|_| Error::MissingOperand(line))
+}
+
+fn string(line: usize, word: &str) -> Result<String, Error> {
+  parse_string(word)
+    .ok_or_else(|| Error::InvalidOperand(line, word.to_string()))
+}
+
+fn integer(line: usize, word: &str) -> Result<u64, Error> {
+  parse_integer(word)
+    .ok_or_else(|| Error::InvalidOperand(line, word.to_string()))
+}
+
+fn hex(line: usize, word: &str) -> Result<Vec<u8>, Error> {
+  let bytes: Option<Vec<u8>> = (0..word.len())
+    .step_by(2)
+    .map(|index| {
+      let digits = word.get(index..index + 2)?;
+      u8::from_str_radix(digits, 16).ok()
+    })
+    .collect();
+  bytes.ok_or_else(|| Error::InvalidOperand(line, word.to_string()))
+}
+
+// Splits the line into words at whitespace, up to a `;` that starts a comment.
+// A string literal is a single word including its quotes, so it may contain
+// either. Returns None if a string literal is not terminated.
+fn words(line: &str) -> Option<Vec<&str>> {
+  let mut words = Vec::new();
+  let mut chars = line.char_indices().peekable();
+
+  while let Some(&(start, c)) = chars.peek() {
+    if c == ';' {
+      break;
+    }
+    if c.is_whitespace() {
+      chars.next();
+      continue;
+    }
+
+    let mut end = line.len();
+    if c == '"' {
+      chars.next();
+      let mut is_terminated = false;
+      while let Some((index, c)) = chars.next() {
+        match c {
+          '\\' => {
+            chars.next();
+          }
+          '"' => {
+            end = index + 1;
+            is_terminated = true;
+            break;
+          }
+          _ => {}
+        }
+      }
+      if !is_terminated {
+        return None;
+      }
+    } else {
+      while let Some(&(index, c)) = chars.peek() {
+        if c.is_whitespace() || c == ';' {
+          end = index;
+          break;
+        }
+        chars.next();
+      }
+    }
+    words.push(&line[start..end]);
+  }
+
+  Some(words)
+}
+
+fn is_identifier(word: &str) -> bool {
+  let mut chars = word.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+      chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+    _ => false,
+  }
+}
+
+fn parse_instruction(mnemonic: &str) -> Option<Instruction> {
+  (0..=u8::MAX)
+    .filter_map(Instruction::from_u8)
+    .find(|instruction| instruction.to_string() == mnemonic)
+}
+
+fn parse_integer(word: &str) -> Option<u64> {
+  match word.strip_prefix("0x") {
+    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+    None => word.parse().ok(),
+  }
+}
+
+// Parses a string literal with the escapes that `{:?}` writes.
+fn parse_string(word: &str) -> Option<String> {
+  let inner = word.strip_prefix('"')?.strip_suffix('"')?;
+  let mut string = String::new();
+  let mut chars = inner.chars();
+
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      string.push(c);
+      continue;
+    }
+    let escaped = match chars.next()? {
+      '0' => '\0',
+      'n' => '\n',
+      'r' => '\r',
+      't' => '\t',
+      c @ ('\\' | '"' | '\'') => c,
+      'u' => {
+        let rest = chars.as_str().strip_prefix('{')?;
+        let (digits, rest) = rest.split_once('}')?;
+        chars = rest.chars();
+        char::from_u32(u32::from_str_radix(digits, 16).ok()?)?
+      }
+      _ => return None,
+    };
+    string.push(escaped);
+  }
+
+  Some(string)
+}
+
+// Ints are written as plain integers and floats always have a decimal point or
+// an exponent, as in `literal`.
+fn parse_constant(word: &str) -> Option<Constant> {
+  if word.starts_with('"') {
+    return parse_string(word).map(Constant::String);
+  }
+  match word {
+    "true" => Some(Constant::Bool(true)),
+    "false" => Some(Constant::Bool(false)),
+    _ => match word.parse() {
+      Ok(int) => Some(Constant::Int(int)),
+      Err(_) => word.parse().ok().map(Constant::Float),
+    },
+  }
+}
+
+fn parse_location(word: &str) -> Option<Location> {
+  let (line, column) = word.split_once(':')?;
+  Some(Location {
+    line: line.parse().ok()?,
+    column: column.parse().ok()?,
+  })
+}
+
+fn literal(constant: &Constant) -> String {
+  match constant {
+    Constant::Float(float) => format!("{:?}", float),
+    constant => constant.to_string(),
+  }
+}
+
+// A line of a chunk's code, which is either an instruction with its operands
+// or a byte that is written out as is.
+enum Line {
+  Instruction(Instruction, Vec<u64>),
+  Byte(u8),
+}
+
+struct Disassembly<'a>(&'a Executable);
+
+impl fmt::Display for Disassembly<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let executable = self.0;
+
+    if !executable.file().is_empty() {
+      writeln!(f, ".file {:?}", executable.file())?;
+    }
+    writeln!(f, ".entry {}", executable.entry())?;
+    for (name, bytes) in executable.sections() {
+      write!(f, ".section {:?}", name)?;
+      if !bytes.is_empty() {
+        write!(f, " ")?;
+      }
+      for byte in bytes {
+        write!(f, "{:02x}", byte)?;
+      }
+      writeln!(f)?;
+    }
+
+    for structure in executable.structs() {
+      writeln!(f, "\n.struct {:?}", structure.name)?;
+      for field in structure.fields.iter() {
+        writeln!(f, "  .field {:?}", field)?;
+      }
+      let mut methods: Vec<_> = structure.methods.iter().collect();
+      methods.sort();
+      for (name, chunk) in methods {
+        writeln!(f, "  .method {:?} {}", name, chunk)?;
+      }
+    }
+
+    for chunk in executable.chunks() {
+      writeln!(f)?;
+      write_chunk(f, executable, chunk)?;
+    }
+
+    Ok(())
+  }
+}
+
+fn write_chunk(
+  f: &mut fmt::Formatter,
+  executable: &Executable,
+  chunk: &Chunk,
+) -> fmt::Result {
+  writeln!(f, ".chunk {:?}", chunk.name())?;
+  writeln!(f, "  .arity {}", chunk.arity())?;
+  for local in chunk.locals() {
+    writeln!(f, "  .local {:?}", local)?;
+  }
+  for capture in chunk.captures() {
+    let kind = if capture.is_local { "local" } else { "upvalue" };
+    writeln!(f, "  .capture {} {}", kind, capture.index)?;
+  }
+  for constant in (0..).map_while(|index| chunk.constant(index)) {
+    writeln!(f, "  .constant {}", literal(constant))?;
+  }
+
+  let code = chunk.code();
+  let lines = lines(chunk);
+
+  // Targets past the end of the code are left as offsets.
+  let mut targets: BTreeSet<usize> = chunk
+    .handlers()
+    .iter()
+    .flat_map(|handler| [handler.start, handler.end, handler.target])
+    .filter(|&offset| offset <= code.len())
+    .collect();
+  for (_, line) in lines.iter() {
+    if let Line::Instruction(
+      Instruction::Jump | Instruction::JumpIf,
+      operands,
+    ) = line
+    {
+      if operands[0] <= code.len() as u64 {
+        targets.insert(operands[0] as usize);
+      }
+    }
+  }
+  let labels: HashMap<usize, String> = targets
+    .into_iter()
+    .enumerate()
+    .map(|(index, target)| (target, format!("L{}", index)))
+    .collect();
+
+  for handler in chunk.handlers() {
+    writeln!(
+      f,
+      "  .handler {} {} {}",
+      target(&labels, handler.start as u64),
+      target(&labels, handler.end as u64),
+      target(&labels, handler.target as u64)
+    )?;
+  }
+
+  let mut locations = chunk.locations().iter().peekable();
+  for (offset, line) in lines.iter() {
+    write_marks(f, *offset, &labels, &mut locations)?;
+    match line {
+      Line::Instruction(instruction, operands) => write_instruction(
+        f,
+        executable,
+        chunk,
+        &labels,
+        *instruction,
+        operands,
+      )?,
+      Line::Byte(byte) => writeln!(f, "  .byte {:#04x}", byte)?,
+    }
+  }
+  write_marks(f, code.len(), &labels, &mut locations)?;
+  for (_, location) in locations {
+    writeln!(f, "  .location {}", location)?;
+  }
+
+  Ok(())
+}
+
+// Writes the label and locations that start at the offset.
+fn write_marks<'a, I>(
+  f: &mut fmt::Formatter,
+  offset: usize,
+  labels: &HashMap<usize, String>,
+  locations: &mut Peekable<I>,
+) -> fmt::Result
+where
+  I: Iterator<Item = &'a (usize, Location)>,
+{
+  if let Some(label) = labels.get(&offset) {
+    writeln!(f, "{}:", label)?;
+  }
+  while let Some((_, location)) =
+    locations.next_if(|&&(start, _)| start <= offset)
+  {
+    writeln!(f, "  .location {}", location)?;
+  }
+  Ok(())
+}
+
+fn write_instruction(
+  f: &mut fmt::Formatter,
+  executable: &Executable,
+  chunk: &Chunk,
+  labels: &HashMap<usize, String>,
+  instruction: Instruction,
+  operands: &[u64],
+) -> fmt::Result {
+  write!(f, "  {}", instruction)?;
+  match instruction {
+    Instruction::Jump | Instruction::JumpIf => {
+      write!(f, " {}", target(labels, operands[0]))?
+    }
+    // The assembler resolves a name to the last local with it.
+    Instruction::PushLocal | Instruction::SetLocal => {
+      let locals = chunk.locals();
+      match locals.get(operands[0] as usize) {
+        Some(name)
+          if is_identifier(name)
+            && locals.iter().rposition(|local| local == name)
+              == Some(operands[0] as usize) =>
+        {
+          write!(f, " {}", name)?
+        }
+        _ => write!(f, " {}", operands[0])?,
+      }
+    }
+    _ => {
+      for operand in operands {
+        write!(f, " {}", operand)?;
+      }
+    }
+  }
+
+  let index = operands.first().map_or(0, |&index| index as usize);
+  let comment = match instruction {
+    Instruction::PushConstant
+    | Instruction::GetField
+    | Instruction::SetField
+    | Instruction::CallNative
+    | Instruction::Invoke => chunk.constant(index).map(literal),
+    Instruction::PushFunction | Instruction::MakeClosure => executable
+      .chunk(index)
+      .map(|chunk| format!("{:?}", chunk.name())),
+    Instruction::MakeRecord => executable
+      .structure(index)
+      .map(|structure| format!("{:?}", structure.name)),
+    _ => None,
+  };
+  match comment {
+    Some(comment) => writeln!(f, " ; {}", comment),
+    None => writeln!(f),
+  }
+}
+
+// Returns the label at the offset, or the offset itself if it has none.
+fn target(labels: &HashMap<usize, String>, offset: u64) -> String {
+  match usize::try_from(offset)
+    .ok()
+    .and_then(|offset| labels.get(&offset))
+  {
+    Some(label) => label.clone(),
+    None => offset.to_string(),
+  }
+}
+
+// Decodes the chunk's code into lines. Instructions that the assembler would
+// encode differently, or that a jump, location or handler points into, are
+// split into their bytes.
+fn lines(chunk: &Chunk) -> Vec<(usize, Line)> {
+  let code = chunk.code();
+
+  let mut decoded = Vec::new();
+  let mut offset = 0;
+  while offset < code.len() {
+    let (instruction, next) = match decode(code, offset) {
+      Some((instruction, operands, next)) => {
+        (Some((instruction, operands)), next)
+      }
+      None => (None, offset + 1),
+    };
+    decoded.push((offset, instruction, next));
+    offset = next;
+  }
+
+  let mut starts: BTreeSet<usize> = chunk
+    .locations()
+    .iter()
+    .map(|&(offset, _)| offset)
+    .collect();
+  for handler in chunk.handlers() {
+    starts.extend([handler.start, handler.end, handler.target]);
+  }
+  for (_, instruction, _) in decoded.iter() {
+    if let Some((Instruction::Jump | Instruction::JumpIf, operands)) =
+      instruction
+    {
+      starts.insert(operands[0] as usize);
+    }
+  }
+
+  let mut lines = Vec::new();
+  for (offset, instruction, next) in decoded {
+    match instruction {
+      Some((instruction, operands))
+        if starts.range(offset + 1..next).next().is_none()
+          && encode(instruction, &operands).as_deref()
+            == Some(&code[offset..next]) =>
+      {
+        lines.push((offset, Line::Instruction(instruction, operands)))
+      }
+      _ => lines.extend(
+        (offset..next).map(|offset| (offset, Line::Byte(code[offset]))),
+      ),
+    }
+  }
+  lines
+}
+
+fn decode(
+  code: &[u8],
+  offset: usize,
+) -> Option<(Instruction, Vec<u64>, usize)> {
+  let instruction = Instruction::from_u8(code[offset])?;
+  let mut operands = Vec::new();
+  let mut next = offset + 1;
+  for _ in 0..instruction.operands() {
+    let (operand, len) = instruction::decode_operand(&code[next..])?;
+    operands.push(operand);
+    next += len;
+  }
+  Some((instruction, operands, next))
+}
+
+// Encodes the instruction as the assembler does, with jump targets padded so
+// that they can be patched.
+fn encode(instruction: Instruction, operands: &[u64]) -> Option<Vec<u8>> {
+  let mut bytes = vec![instruction as u8];
+  match instruction {
+    Instruction::Jump | Instruction::JumpIf => bytes.extend(
+      instruction::encode_padded_operand::<JUMP_OPERAND_LEN>(operands[0])?,
+    ),
+    _ => {
+      for &operand in operands {
+        instruction::encode_operand(operand, &mut bytes);
+      }
+    }
+  }
+  Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use crate::{
+    executable::{
+      Capture, Chunk, Constant, Executable, Handler, Location, Struct,
+    },
+    instruction::Instruction,
+    machine::Machine,
+    value::Value,
+  };
+
+  use super::{assemble, disassemble, Error};
+
+  const SUM: &str = r#"
+    .chunk "main"
+      .arity 0
+      .local "main"
+      .local "sum"
+      .local "i"
+      .constant 0
+      .constant 1
+      .constant 10
+      PushConstant 0
+      PushConstant 1
+    loop:
+      PushLocal i
+      PushConstant 2
+      Greater
+      JumpIf done ; past the last number
+      PushLocal sum
+      PushLocal i
+      Add
+      SetLocal sum
+      Pop
+      PushLocal i
+      PushConstant 1
+      Add
+      SetLocal i
+      Pop
+      Jump loop
+    done:
+      PushLocal sum
+      Return
+  "#;
+
+  #[test]
+  fn assembly() {
+    let executable = assemble(SUM).expect("failed to assemble");
+    assert_eq!(Machine::new().execute(&executable).unwrap(), Value::Int(55));
+    assert_eq!(assemble(&disassemble(&executable)), Ok(executable));
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut main = Chunk::new();
+    main.set_name("main".to_string());
+    main.add_local("main".to_string());
+    main.add_local("x".to_string());
+    main.add_local("x".to_string());
+    main.add_capture(Capture {
+      is_local: false,
+      index: 1,
+    });
+    main.add_constant(Constant::Int(-3));
+    main.add_constant(Constant::Float(3.0));
+    main.add_constant(Constant::Float(f64::INFINITY));
+    main.add_constant(Constant::Bool(false));
+    main.add_constant(Constant::String("a \"b\"; \\ \n \u{7f} é".to_string()));
+
+    main.add_location(Location { line: 2, column: 5 });
+    let jump = main.emit_jump(Instruction::JumpIf);
+    main.patch_jump(jump, 1 << 20);
+    main.emit(Instruction::PushLocal);
+    main.emit_operand(1);
+    main.emit(Instruction::SetLocal);
+    main.emit_operand(2);
+    // An unpadded jump, an invalid instruction and a jump into the operand of
+    // another instruction.
+    main.emit(Instruction::Jump);
+    main.emit_operand(0);
+    main.emit_byte(0xff);
+    let jump = main.emit_jump(Instruction::Jump);
+    main.patch_jump(jump, jump + 1);
+    main.add_location(Location { line: 3, column: 1 });
+    let call = main.emit(Instruction::CallNative);
+    main.emit_operand(4);
+    main.emit_operand(300);
+    main.emit(Instruction::Return);
+    main.add_handler(Handler {
+      start: 0,
+      end: call,
+      target: main.code().len(),
+    });
+
+    let mut method = Chunk::new();
+    method.set_name("Point::norm".to_string());
+    method.set_arity(1);
+    method.emit(Instruction::PushLocal);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(main);
+    executable.add_chunk(method);
+    executable.add_struct(Struct {
+      name: "Point".to_string(),
+      fields: vec!["x".to_string(), "y".to_string()],
+      methods: HashMap::from([("norm".to_string(), 1)]),
+    });
+    executable.set_file("main.oma".to_string());
+    executable.set_section("debug".to_string(), vec![0, 1, 0xff]);
+    executable.set_section("empty".to_string(), Vec::new());
+
+    assert_eq!(assemble(&disassemble(&executable)), Ok(executable));
+  }
+
+  #[test]
+  fn errors() {
+    for (source, error) in [
+      (
+        ".chunk \"main\"\n  Jump end",
+        Error::UndefinedLabel(2, "end".into()),
+      ),
+      ("PushUnit", Error::OutsideChunk(1)),
+      (
+        ".chunk \"main\"\n  Add 1",
+        Error::UnexpectedOperand(2, "1".into()),
+      ),
+      (
+        ".chunk \"main\"\n  PushLocal x",
+        Error::UndefinedLocal(2, "x".into()),
+      ),
+      (
+        ".chunk \"main\"\n  Push",
+        Error::InvalidInstruction(2, "Push".into()),
+      ),
+      (".field \"x\"", Error::OutsideStruct(1)),
+      (".chunk \"main", Error::InvalidString(1)),
+      (".chunk \"\"\na:\na:", Error::DuplicateLabel(3, "a".into())),
+    ] {
+      assert_eq!(assemble(source), Err(error));
+    }
+  }
+}
@@ -1,57 +1,282 @@
 use std::{
-  collections::HashMap,
+  collections::{BTreeMap, HashMap},
   fmt,
   io::{self, Read},
 };
 
 use num_traits::FromPrimitive;
 
-use crate::instruction::Instruction;
+use crate::instruction::{self, Instruction, JUMP_OPERAND_LEN};
+
+// The first bytes of every serialized executable.
+pub const MAGIC: [u8; 4] = *b"OMAC";
+// Bumped whenever the layout of a serialized executable changes, since older
+// loaders cannot make sense of it.
+pub const VERSION: u64 = 8;
 
 #[derive(Debug)]
 pub enum ParseError {
+  InvalidMagic([u8; 4]),
+  UnsupportedVersion(u64),
+  InvalidEntry(u64),
   InvalidConstantType(u64),
   InvalidConstantValue(u64),
+  InvalidCapture(u64),
   InvalidInstruction(u64),
+  InvalidString,
   Io(io::Error),
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Executable {
   chunks: Vec<Chunk>,
+  structs: Vec<Struct>,
+  entry: usize,
+  // The path of the source file the executable was compiled from, or empty if
+  // it is not known.
+  file: String,
+  // Extra data such as debug information, keyed by name. Loaders keep
+  // sections they do not recognize without interpreting them.
+  sections: BTreeMap<String, Vec<u8>>,
 }
 
 impl Executable {
   pub fn new() -> Executable {
-    Executable { chunks: Vec::new() }
+    Executable {
+      chunks: Vec::new(),
+      structs: Vec::new(),
+      entry: 0,
+      file: String::new(),
+      sections: BTreeMap::new(),
+    }
+  }
+
+  pub fn add_chunk(&mut self, chunk: Chunk) -> usize {
+    self.chunks.push(chunk);
+    self.chunks.len() - 1
+  }
+
+  pub fn add_struct(&mut self, structure: Struct) -> usize {
+    self.structs.push(structure);
+    self.structs.len() - 1
+  }
+
+  pub fn set_entry(&mut self, entry: usize) {
+    self.entry = entry;
+  }
+
+  pub fn chunk(&self, index: usize) -> Option<&Chunk> {
+    self.chunks.get(index)
+  }
+
+  pub fn chunks(&self) -> &[Chunk] {
+    &self.chunks
+  }
+
+  pub fn structure(&self, index: usize) -> Option<&Struct> {
+    self.structs.get(index)
+  }
+
+  pub fn structs(&self) -> &[Struct] {
+    &self.structs
+  }
+
+  pub fn entry(&self) -> usize {
+    self.entry
+  }
+
+  pub fn set_file(&mut self, file: String) {
+    self.file = file;
+  }
+
+  pub fn file(&self) -> &str {
+    &self.file
+  }
+
+  pub fn set_section(&mut self, name: String, bytes: Vec<u8>) {
+    self.sections.insert(name, bytes);
+  }
+
+  pub fn section(&self, name: &str) -> Option<&[u8]> {
+    self.sections.get(name).map(|bytes| bytes.as_slice())
+  }
+
+  pub fn sections(&self) -> &BTreeMap<String, Vec<u8>> {
+    &self.sections
+  }
+
+  pub fn from_bytes<R>(r: &mut R) -> Result<Executable, ParseError>
+  where
+    R: Read,
+  {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic).map_err(ParseError::Io)?;
+    if magic != MAGIC {
+      return Err(ParseError::InvalidMagic(magic));
+    }
+    let version = read_u64(r)?;
+    if version != VERSION {
+      return Err(ParseError::UnsupportedVersion(version));
+    }
+
+    let mut executable = Executable::new();
+
+    let entry = read_u64(r)?;
+    executable.file = read_string(r)?;
+
+    let chunks_len = read_u64(r)?;
+    for _ in 0..chunks_len {
+      executable.chunks.push(Chunk::from_bytes(r)?);
+    }
+    // An executable without chunks keeps the default entry, so that it can be
+    // loaded back after being written, but it cannot be started.
+    if entry >= chunks_len.max(1) {
+      return Err(ParseError::InvalidEntry(entry));
+    }
+    executable.entry = entry as usize;
+
+    let structs_len = read_u64(r)?;
+    for _ in 0..structs_len {
+      executable.structs.push(Struct::from_bytes(r)?);
+    }
+
+    let sections_len = read_u64(r)?;
+    for _ in 0..sections_len {
+      let name = read_string(r)?;
+      let bytes = read_bytes(r)?;
+      executable.sections.insert(name, bytes);
+    }
+
+    Ok(executable)
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    bytes.extend(MAGIC);
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend((self.entry as u64).to_le_bytes());
+    write_string(&mut bytes, &self.file);
+
+    bytes.extend((self.chunks.len() as u64).to_le_bytes());
+    for chunk in self.chunks.iter() {
+      bytes.extend(chunk.to_bytes());
+    }
+
+    bytes.extend((self.structs.len() as u64).to_le_bytes());
+    for structure in self.structs.iter() {
+      bytes.extend(structure.to_bytes());
+    }
+
+    bytes.extend((self.sections.len() as u64).to_le_bytes());
+    for (name, section) in self.sections.iter() {
+      write_string(&mut bytes, name);
+      bytes.extend((section.len() as u64).to_le_bytes());
+      bytes.extend(section);
+    }
+
+    bytes
+  }
+}
+
+impl fmt::Display for Executable {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (index, structure) in self.structs.iter().enumerate() {
+      writeln!(f, "struct {:#010x}: {}", index, structure)?;
+    }
+    if !self.structs.is_empty() && !self.chunks.is_empty() {
+      writeln!(f)?;
+    }
+    for (index, chunk) in self.chunks.iter().enumerate() {
+      if index > 0 {
+        writeln!(f)?;
+      }
+      if chunk.name.is_empty() {
+        writeln!(f, "chunk {:#010x}:", index)?;
+      } else {
+        writeln!(f, "chunk {:#010x} {}:", index, chunk.name)?;
+      }
+      write!(f, "{}", chunk)?;
+    }
+    Ok(())
   }
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Chunk {
-  locals: HashMap<String, usize>,
+  name: String,
+  arity: usize,
+  captures: Vec<Capture>,
+  // The name of the local in each slot, for disassembly.
+  locals: Vec<String>,
   constants: Vec<Constant>,
   code: Vec<u8>,
+  // Each entry gives the location of the instructions from its offset up to
+  // the offset of the next entry.
+  locations: Vec<(usize, Location)>,
+  handlers: Vec<Handler>,
 }
 
 impl Chunk {
   pub fn new() -> Chunk {
     Chunk {
-      locals: HashMap::new(),
+      name: String::new(),
+      arity: 0,
+      captures: Vec::new(),
+      locals: Vec::new(),
       constants: Vec::new(),
       code: Vec::new(),
+      locations: Vec::new(),
+      handlers: Vec::new(),
     }
   }
 
+  // Closures are left without a name.
+  pub fn set_name(&mut self, name: String) {
+    self.name = name;
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
   pub fn add_constant(&mut self, constant: Constant) -> usize {
     self.constants.push(constant);
     self.constants.len() - 1
   }
 
   pub fn add_local(&mut self, identifier: String) -> usize {
-    let index = self.locals.len();
-    self.locals.insert(identifier, index);
-    index
+    self.locals.push(identifier);
+    self.locals.len() - 1
+  }
+
+  // Names the local in the slot, which is reused by later locals once the one
+  // in it goes out of scope.
+  pub fn set_local(&mut self, slot: usize, identifier: String) {
+    if slot < self.locals.len() {
+      self.locals[slot] = identifier;
+    } else {
+      self.locals.resize(slot, String::new());
+      self.locals.push(identifier);
+    }
+  }
+
+  // Parameters occupy the local slots directly after the callee, so they must
+  // be added before any other locals.
+  pub fn add_parameter(&mut self, identifier: String) -> usize {
+    self.arity += 1;
+    self.add_local(identifier)
+  }
+
+  // Sets the arity without naming the parameters, for chunks that are
+  // assembled rather than generated.
+  pub fn set_arity(&mut self, arity: usize) {
+    self.arity = arity;
+  }
+
+  pub fn add_capture(&mut self, capture: Capture) -> usize {
+    self.captures.push(capture);
+    self.captures.len() - 1
   }
 
   pub fn emit(&mut self, instruction: Instruction) -> usize {
@@ -64,45 +289,98 @@ impl Chunk {
     self.code.len() - 1
   }
 
-  pub fn emit_bytes<const N: usize>(&mut self, bytes: [u8; N]) -> usize {
-    for byte in bytes {
-      self.code.push(byte);
-    }
-    self.code.len() - N
+  pub fn emit_operand(&mut self, operand: u64) -> usize {
+    let offset = self.code.len();
+    instruction::encode_operand(operand, &mut self.code);
+    offset
   }
 
-  pub fn patch_bytes<const N: usize>(
-    &mut self,
-    index: usize,
-    bytes: [u8; N],
-  ) -> usize {
-    for (offset, patch_byte) in bytes.iter().enumerate() {
-      self
-        .code
-        .get_mut(index + offset)
-        .map(|byte| *byte = *patch_byte);
+  // Emits the jump with a placeholder target, returning the offset of the
+  // operand to patch once the target is known.
+  pub fn emit_jump(&mut self, instruction: Instruction) -> usize {
+    self.emit(instruction);
+    let offset = self.code.len();
+    self.code.extend(jump_operand(0));
+    offset
+  }
+
+  pub fn patch_jump(&mut self, offset: usize, target: usize) {
+    self.code[offset..offset + JUMP_OPERAND_LEN]
+      .copy_from_slice(&jump_operand(target));
+  }
+
+  // Sets the location of the instructions emitted from now on.
+  pub fn add_location(&mut self, location: Location) {
+    let offset = self.code.len();
+    match self.locations.last_mut() {
+      Some((_, last)) if *last == location => {}
+      Some((start, last)) if *start == offset => *last = location,
+      _ => self.locations.push((offset, location)),
     }
-    index
   }
 
-  pub fn local(&self, identifier: &str) -> Option<usize> {
-    self.locals.get(identifier).copied()
+  // Returns the location of the instruction that the offset falls within.
+  pub fn location(&self, offset: usize) -> Option<Location> {
+    let index = self
+      .locations
+      .partition_point(|&(start, _)| start <= offset);
+    index.checked_sub(1).map(|index| self.locations[index].1)
+  }
+
+  pub fn locations(&self) -> &[(usize, Location)] {
+    &self.locations
+  }
+
+  // Handlers are searched in the order they were added, so inner handlers must
+  // be added before the handlers that enclose them.
+  pub fn add_handler(&mut self, handler: Handler) -> usize {
+    self.handlers.push(handler);
+    self.handlers.len() - 1
   }
 
-  pub fn constant(&self, index: usize) -> Option<Constant> {
-    self.constants.get(index).cloned()
+  pub fn handlers(&self) -> &[Handler] {
+    &self.handlers
+  }
+
+  pub fn locals(&self) -> &[String] {
+    &self.locals
+  }
+
+  pub fn constant(&self, index: usize) -> Option<&Constant> {
+    self.constants.get(index)
   }
 
   pub fn code(&self) -> &[u8] {
     &self.code
   }
 
+  pub fn arity(&self) -> usize {
+    self.arity
+  }
+
+  pub fn captures(&self) -> &[Capture] {
+    &self.captures
+  }
+
   pub fn from_bytes<R>(r: &mut R) -> Result<Chunk, ParseError>
   where
     R: Read,
   {
     let mut chunk = Chunk::new();
 
+    chunk.name = read_string(r)?;
+    chunk.arity = read_u64(r)? as usize;
+
+    let captures_len = read_u64(r)?;
+    for _ in 0..captures_len {
+      chunk.captures.push(Capture::from_bytes(r)?);
+    }
+
+    let locals_len = read_u64(r)?;
+    for _ in 0..locals_len {
+      chunk.locals.push(read_string(r)?);
+    }
+
     let constants_len = read_u64(r)?;
     for _ in 0..constants_len {
       chunk.constants.push(Constant::from_bytes(r)?);
@@ -114,12 +392,40 @@ impl Chunk {
       chunk.code.push(byte);
     }
 
+    let locations_len = read_u64(r)?;
+    for _ in 0..locations_len {
+      let offset = read_u64(r)? as usize;
+      let line = read_u64(r)? as usize;
+      let column = read_u64(r)? as usize;
+      chunk.locations.push((offset, Location { line, column }));
+    }
+
+    let handlers_len = read_u64(r)?;
+    for _ in 0..handlers_len {
+      chunk.handlers.push(Handler::from_bytes(r)?);
+    }
+
     Ok(chunk)
   }
 
   pub fn to_bytes(&self) -> Vec<u8> {
     let mut bytes = Vec::new();
 
+    write_string(&mut bytes, &self.name);
+    bytes.extend((self.arity as u64).to_le_bytes());
+
+    let captures_len = self.captures.len() as u64;
+    bytes.extend(captures_len.to_le_bytes());
+    for capture in self.captures.iter() {
+      bytes.extend(capture.to_bytes());
+    }
+
+    let locals_len = self.locals.len() as u64;
+    bytes.extend(locals_len.to_le_bytes());
+    for identifier in self.locals.iter() {
+      write_string(&mut bytes, identifier);
+    }
+
     let constants_len = self.constants.len() as u64;
     bytes.extend(constants_len.to_le_bytes());
     for constant in self.constants.iter() {
@@ -130,6 +436,20 @@ impl Chunk {
     bytes.extend(code_len.to_le_bytes());
     bytes.extend(self.code.clone());
 
+    let locations_len = self.locations.len() as u64;
+    bytes.extend(locations_len.to_le_bytes());
+    for (offset, location) in self.locations.iter() {
+      bytes.extend((*offset as u64).to_le_bytes());
+      bytes.extend((location.line as u64).to_le_bytes());
+      bytes.extend((location.column as u64).to_le_bytes());
+    }
+
+    let handlers_len = self.handlers.len() as u64;
+    bytes.extend(handlers_len.to_le_bytes());
+    for handler in self.handlers.iter() {
+      bytes.extend(handler.to_bytes());
+    }
+
     bytes
   }
 }
@@ -153,71 +473,68 @@ impl fmt::Display for Chunk {
         }
       };
 
-      match instruction {
-        Instruction::PushConstant => {
-          if offset + 8 <= self.code.len() {
-            let index_bytes = [
-              self.code[offset],
-              self.code[offset + 1],
-              self.code[offset + 2],
-              self.code[offset + 3],
-              self.code[offset + 4],
-              self.code[offset + 5],
-              self.code[offset + 6],
-              self.code[offset + 7],
-            ];
-            let index = u64::from_le_bytes(index_bytes);
-            write!(f, " {:#010x}", index)?;
-            offset += 8;
-
-            if let Some(constant) = self.constants.get(index as usize) {
-              write!(f, " {}", constant)?;
-            } else {
-              write!(f, " Invalid")?;
-            }
+      let mut operands = Vec::new();
+      for _ in 0..instruction.operands() {
+        match instruction::decode_operand(&self.code[offset..]) {
+          Some((operand, len)) => {
+            operands.push(operand);
+            offset += len;
+          }
+          None => break,
+        }
+      }
+
+      match (instruction, operands.as_slice()) {
+        (
+          Instruction::PushConstant
+          | Instruction::GetField
+          | Instruction::SetField,
+          &[index],
+        ) => {
+          write!(f, " {:#010x}", index)?;
+          if let Some(constant) = self.constants.get(index as usize) {
+            write!(f, " {}", constant)?;
+          } else {
+            write!(f, " Invalid")?;
           }
         }
-        Instruction::PushLocal => {
-          if offset + 8 <= self.code.len() {
-            let index_bytes = [
-              self.code[offset],
-              self.code[offset + 1],
-              self.code[offset + 2],
-              self.code[offset + 3],
-              self.code[offset + 4],
-              self.code[offset + 5],
-              self.code[offset + 6],
-              self.code[offset + 7],
-            ];
-            let index = u64::from_le_bytes(index_bytes);
-            write!(f, " {:#010x}", index)?;
-            offset += 8;
-
-            if let Some(identifier) =
-              find_key_for_value(&self.locals, index as usize)
-            {
-              write!(f, " {}", identifier)?;
-            } else {
-              write!(f, " Invalid")?;
-            }
+        (Instruction::PushLocal | Instruction::SetLocal, &[index]) => {
+          write!(f, " {:#010x}", index)?;
+          if let Some(identifier) = self.locals.get(index as usize) {
+            write!(f, " {}", identifier)?;
+          } else {
+            write!(f, " Invalid")?;
           }
         }
-        Instruction::Jump | Instruction::JumpIf => {
-          if offset + 8 <= self.code.len() {
-            let index_bytes = [
-              self.code[offset],
-              self.code[offset + 1],
-              self.code[offset + 2],
-              self.code[offset + 3],
-              self.code[offset + 4],
-              self.code[offset + 5],
-              self.code[offset + 6],
-              self.code[offset + 7],
-            ];
-            let index = u64::from_le_bytes(index_bytes);
-            write!(f, " {:#010x}", index)?;
-            offset += 8;
+        (
+          Instruction::PushFunction
+          | Instruction::MakeClosure
+          | Instruction::MakeRecord
+          | Instruction::GetUpvalue
+          | Instruction::SetUpvalue
+          | Instruction::Jump
+          | Instruction::JumpIf,
+          &[index],
+        ) => {
+          write!(f, " {:#010x}", index)?;
+        }
+        (Instruction::CallNative | Instruction::Invoke, &[index, arity]) => {
+          write!(f, " {:#010x}", index)?;
+          if let Some(constant) = self.constants.get(index as usize) {
+            write!(f, " {}", constant)?;
+          } else {
+            write!(f, " Invalid")?;
           }
+          write!(f, " {}", arity)?;
+        }
+        (
+          Instruction::Call
+          | Instruction::MakeList
+          | Instruction::MakeMap
+          | Instruction::PopN,
+          &[arity],
+        ) => {
+          write!(f, " {}", arity)?;
         }
         _ => {}
       }
@@ -231,11 +548,162 @@ impl fmt::Display for Chunk {
   }
 }
 
+// A position in the source file, with the line and column counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl fmt::Display for Location {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.line, self.column)
+  }
+}
+
+// The field names of a struct in declaration order, which records of the struct
+// store their field values in, and the chunks of its methods by name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Struct {
+  pub name: String,
+  pub fields: Vec<String>,
+  pub methods: HashMap<String, usize>,
+}
+
+impl Struct {
+  pub fn field(&self, name: &str) -> Option<usize> {
+    self.fields.iter().position(|field| field == name)
+  }
+
+  pub fn method(&self, name: &str) -> Option<usize> {
+    self.methods.get(name).copied()
+  }
+
+  pub fn from_bytes<R>(r: &mut R) -> Result<Struct, ParseError>
+  where
+    R: Read,
+  {
+    let name = read_string(r)?;
+
+    let mut fields = Vec::new();
+    let fields_len = read_u64(r)?;
+    for _ in 0..fields_len {
+      fields.push(read_string(r)?);
+    }
+
+    let mut methods = HashMap::new();
+    let methods_len = read_u64(r)?;
+    for _ in 0..methods_len {
+      let method = read_string(r)?;
+      let index = read_u64(r)? as usize;
+      methods.insert(method, index);
+    }
+
+    Ok(Struct {
+      name,
+      fields,
+      methods,
+    })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    write_string(&mut bytes, &self.name);
+
+    bytes.extend((self.fields.len() as u64).to_le_bytes());
+    for field in self.fields.iter() {
+      write_string(&mut bytes, field);
+    }
+
+    let mut methods: Vec<_> = self.methods.iter().collect();
+    methods.sort();
+    bytes.extend((methods.len() as u64).to_le_bytes());
+    for (method, &index) in methods {
+      write_string(&mut bytes, method);
+      bytes.extend((index as u64).to_le_bytes());
+    }
+
+    bytes
+  }
+}
+
+impl fmt::Display for Struct {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {{ {} }}", self.name, self.fields.join(", "))
+  }
+}
+
+// Catches the values thrown by the instructions from `start` up to `end`,
+// including those thrown by the functions they call. The stack is unwound to
+// the depth it had at `start` before the thrown value is pushed and execution
+// continues at `target`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Handler {
+  pub start: usize,
+  pub end: usize,
+  pub target: usize,
+}
+
+impl Handler {
+  pub fn from_bytes<R>(r: &mut R) -> Result<Handler, ParseError>
+  where
+    R: Read,
+  {
+    let start = read_u64(r)? as usize;
+    let end = read_u64(r)? as usize;
+    let target = read_u64(r)? as usize;
+
+    Ok(Handler { start, end, target })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend((self.start as u64).to_le_bytes());
+    bytes.extend((self.end as u64).to_le_bytes());
+    bytes.extend((self.target as u64).to_le_bytes());
+    bytes
+  }
+}
+
+// Describes a variable captured by a closure over this chunk, which is either a
+// local of the enclosing function or one of the enclosing function's own
+// captures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capture {
+  pub is_local: bool,
+  pub index: usize,
+}
+
+impl Capture {
+  pub fn from_bytes<R>(r: &mut R) -> Result<Capture, ParseError>
+  where
+    R: Read,
+  {
+    let is_local = match read_u8(r)? {
+      0 => false,
+      1 => true,
+      byte => return Err(ParseError::InvalidCapture(byte as u64)),
+    };
+    let index = read_u64(r)? as usize;
+
+    Ok(Capture { is_local, index })
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.push(self.is_local as u8);
+    bytes.extend((self.index as u64).to_le_bytes());
+    bytes
+  }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Constant {
   Int(i64),
   Float(f64),
   Bool(bool),
+  String(String),
 }
 
 impl Constant {
@@ -263,6 +731,7 @@ impl Constant {
           byte => return Err(ParseError::InvalidConstantValue(byte as u64)),
         }
       }
+      4 => Constant::String(read_string(r)?),
       constant => {
         return Err(ParseError::InvalidConstantType(constant));
       }
@@ -291,6 +760,10 @@ impl Constant {
           bytes.push(0);
         }
       }
+      Constant::String(string) => {
+        bytes.extend(4u64.to_le_bytes());
+        write_string(&mut bytes, string);
+      }
     }
 
     bytes
@@ -303,10 +776,16 @@ impl fmt::Display for Constant {
       Constant::Int(int) => write!(f, "{}", int),
       Constant::Float(float) => write!(f, "{}", float),
       Constant::Bool(bool) => write!(f, "{}", bool),
+      Constant::String(string) => write!(f, "{:?}", string),
     }
   }
 }
 
+fn jump_operand(target: usize) -> [u8; JUMP_OPERAND_LEN] {
+  instruction::encode_padded_operand(target as u64)
+    .expect("jump target out of range")
+}
+
 fn read_u8<R>(r: &mut R) -> Result<u8, ParseError>
 where
   R: Read,
@@ -325,23 +804,46 @@ where
   Ok(u64::from_le_bytes(bytes))
 }
 
-fn find_key_for_value(
-  hash_map: &HashMap<String, usize>,
-  value: usize,
-) -> Option<&str> {
-  hash_map
-    .iter()
-    .find_map(|(key, &val)| if val == value { Some(key) } else { None })
-    .map(|identifier| identifier.as_str())
+// The buffer grows as the bytes are read rather than being allocated up front,
+// so that a corrupt length runs out of input instead of memory.
+fn read_bytes<R>(r: &mut R) -> Result<Vec<u8>, ParseError>
+where
+  R: Read,
+{
+  let len = read_u64(r)?;
+  let mut bytes = Vec::new();
+  r.take(len)
+    .read_to_end(&mut bytes)
+    .map_err(ParseError::Io)?;
+  if (bytes.len() as u64) < len {
+    return Err(ParseError::Io(io::ErrorKind::UnexpectedEof.into()));
+  }
+  Ok(bytes)
+}
+
+fn read_string<R>(r: &mut R) -> Result<String, ParseError>
+where
+  R: Read,
+{
+  let bytes = read_bytes(r)?;
+  String::from_utf8(bytes).map_err(|_| ParseError::InvalidString)
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+  bytes.extend((string.len() as u64).to_le_bytes());
+  bytes.extend(string.as_bytes());
 }
 
 #[cfg(test)]
 mod tests {
-  use std::io::Cursor;
+  use std::{collections::HashMap, io::Cursor};
 
   use crate::instruction::Instruction;
 
-  use super::{Chunk, Constant};
+  use super::{
+    Capture, Chunk, Constant, Executable, Handler, Location, ParseError,
+    Struct, VERSION,
+  };
 
   #[test]
   fn constant() {
@@ -354,9 +856,24 @@ mod tests {
     );
   }
 
+  #[test]
+  fn string_constant() {
+    let constant = Constant::String("foo".to_string());
+    let bytes = constant.to_bytes();
+    assert_eq!(
+      constant,
+      Constant::from_bytes(&mut Cursor::new(bytes))
+        .expect("failed to parse constant")
+    );
+  }
+
   #[test]
   fn chunk() {
     let mut chunk = Chunk::new();
+    chunk.add_capture(Capture {
+      is_local: true,
+      index: 1,
+    });
     chunk.add_constant(Constant::Int(42));
     chunk.add_constant(Constant::Float(3.14159));
     chunk.add_constant(Constant::Bool(false));
@@ -369,4 +886,117 @@ mod tests {
         .expect("failed to parse constant")
     );
   }
+
+  #[test]
+  fn executable() {
+    let mut executable = Executable::new();
+
+    let mut chunk = Chunk::new();
+    chunk.set_name("main".to_string());
+    chunk.add_local("main".to_string());
+    chunk.add_parameter("x".to_string());
+    chunk.add_local("y".to_string());
+    chunk.add_constant(Constant::String("foo".to_string()));
+    chunk.emit(Instruction::PushUnit);
+    chunk.add_location(Location { line: 2, column: 5 });
+    chunk.emit(Instruction::Return);
+    chunk.add_handler(Handler {
+      start: 0,
+      end: 1,
+      target: 1,
+    });
+    let main = executable.add_chunk(chunk);
+    executable.add_chunk(Chunk::new());
+    executable.set_entry(main);
+    executable.set_file("main.oma".to_string());
+
+    let mut structure = Struct {
+      name: "Point".to_string(),
+      fields: vec!["x".to_string(), "y".to_string()],
+      methods: HashMap::new(),
+    };
+    structure.methods.insert("norm".to_string(), 1);
+    executable.add_struct(structure);
+    executable.set_section("debug".to_string(), vec![1, 2, 3]);
+
+    let bytes = executable.to_bytes();
+    assert_eq!(
+      executable,
+      Executable::from_bytes(&mut Cursor::new(bytes))
+        .expect("failed to parse executable")
+    );
+  }
+
+  #[test]
+  fn locations() {
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushUnit);
+    chunk.add_location(Location { line: 1, column: 1 });
+    chunk.emit(Instruction::PushUnit);
+    chunk.add_location(Location { line: 1, column: 1 });
+    chunk.emit(Instruction::PushUnit);
+    chunk.add_location(Location { line: 2, column: 1 });
+    chunk.add_location(Location { line: 3, column: 4 });
+    chunk.emit(Instruction::Add);
+
+    assert_eq!(chunk.locations().len(), 2);
+    assert_eq!(chunk.location(0), None);
+    assert_eq!(chunk.location(2), Some(Location { line: 1, column: 1 }));
+    assert_eq!(chunk.location(3), Some(Location { line: 3, column: 4 }));
+  }
+
+  #[test]
+  fn version() {
+    let mut bytes = Executable::new().to_bytes();
+    bytes[4..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert!(matches!(
+      Executable::from_bytes(&mut Cursor::new(bytes)),
+      Err(ParseError::UnsupportedVersion(version)) if version == VERSION + 1
+    ));
+
+    let mut bytes = Executable::new().to_bytes();
+    bytes[0] = b'X';
+    assert!(matches!(
+      Executable::from_bytes(&mut Cursor::new(bytes)),
+      Err(ParseError::InvalidMagic(_))
+    ));
+  }
+
+  #[test]
+  fn corrupt() {
+    let mut executable = Executable::new();
+    executable.add_chunk(Chunk::new());
+    let bytes = executable.to_bytes();
+
+    // The length of the file name claims far more bytes than there are.
+    let mut corrupt = bytes.clone();
+    corrupt[20..28].copy_from_slice(&(1u64 << 62).to_le_bytes());
+    assert!(matches!(
+      Executable::from_bytes(&mut Cursor::new(corrupt)),
+      Err(ParseError::Io(_))
+    ));
+
+    let mut corrupt = bytes.clone();
+    corrupt[12..20].copy_from_slice(&1u64.to_le_bytes());
+    assert!(matches!(
+      Executable::from_bytes(&mut Cursor::new(corrupt)),
+      Err(ParseError::InvalidEntry(1))
+    ));
+
+    let mut empty = Executable::new().to_bytes();
+    empty[12..20].copy_from_slice(&1u64.to_le_bytes());
+    assert!(matches!(
+      Executable::from_bytes(&mut Cursor::new(empty)),
+      Err(ParseError::InvalidEntry(1))
+    ));
+  }
+
+  #[test]
+  fn empty() {
+    let executable = Executable::new();
+    assert_eq!(
+      Executable::from_bytes(&mut Cursor::new(executable.to_bytes())).unwrap(),
+      executable
+    );
+  }
 }
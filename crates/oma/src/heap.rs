@@ -0,0 +1,386 @@
+use std::{collections::BTreeMap, mem};
+
+use crate::value::{Key, Value};
+
+const INITIAL_THRESHOLD: usize = 1024 * 1024;
+const GROWTH_FACTOR: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Handle(usize);
+
+#[derive(Debug)]
+pub enum Object {
+  String(String),
+  List(Vec<Value>),
+  Map(BTreeMap<Key, Value>),
+  Record(Record),
+  Closure(Closure),
+  Upvalue(Upvalue),
+}
+
+impl Object {
+  fn size(&self) -> usize {
+    let size = match self {
+      Object::String(string) => string.capacity(),
+      Object::List(list) => list.capacity() * mem::size_of::<Value>(),
+      Object::Map(map) => {
+        map.len() * (mem::size_of::<Key>() + mem::size_of::<Value>())
+      }
+      Object::Record(record) => {
+        record.fields.capacity() * mem::size_of::<Value>()
+      }
+      Object::Closure(closure) => {
+        closure.upvalues.capacity() * mem::size_of::<Handle>()
+      }
+      Object::Upvalue(_) => 0,
+    };
+    mem::size_of::<Object>() + size
+  }
+
+  fn trace(&self, gray: &mut Vec<Handle>) {
+    match self {
+      Object::String(_) => {}
+      Object::List(list) => gray.extend(list.iter().filter_map(Value::handle)),
+      Object::Map(map) => gray.extend(map.values().filter_map(Value::handle)),
+      Object::Record(record) => {
+        gray.extend(record.fields.iter().filter_map(Value::handle))
+      }
+      Object::Closure(closure) => gray.extend(closure.upvalues.iter()),
+      Object::Upvalue(Upvalue::Open(_)) => {}
+      Object::Upvalue(Upvalue::Closed(value)) => gray.extend(value.handle()),
+    }
+  }
+}
+
+// An instance of a struct, with its fields stored in declaration order.
+#[derive(Debug)]
+pub struct Record {
+  pub structure: usize,
+  pub fields: Vec<Value>,
+}
+
+#[derive(Debug)]
+pub struct Closure {
+  pub function: usize,
+  pub upvalues: Vec<Handle>,
+}
+
+// An upvalue stays open and refers to its stack slot for as long as the frame
+// that owns the slot is alive, and is closed over a copy of the value once the
+// frame returns.
+#[derive(Debug)]
+pub enum Upvalue {
+  Open(usize),
+  Closed(Value),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+  pub collections: usize,
+  pub allocated: usize,
+  pub freed: usize,
+  pub live: usize,
+  pub bytes: usize,
+}
+
+// The size is the one counted towards the stats, which the object may have
+// outgrown since it was counted.
+struct Entry {
+  object: Object,
+  size: usize,
+  is_marked: bool,
+}
+
+pub struct Heap {
+  entries: Vec<Option<Entry>>,
+  free: Vec<usize>,
+  threshold: usize,
+  is_stress: bool,
+  stats: Stats,
+}
+
+impl Heap {
+  pub fn new() -> Heap {
+    Heap {
+      entries: Vec::new(),
+      free: Vec::new(),
+      threshold: INITIAL_THRESHOLD,
+      is_stress: false,
+      stats: Stats::default(),
+    }
+  }
+
+  // Collects garbage on every allocation, which flushes out values that are
+  // not reachable from the roots while they are still in use.
+  pub fn set_stress(&mut self, is_stress: bool) {
+    self.is_stress = is_stress;
+  }
+
+  pub fn stats(&self) -> Stats {
+    self.stats
+  }
+
+  pub fn get(&self, handle: Handle) -> &Object {
+    &self
+      .entries
+      .get(handle.0)
+      .and_then(|entry| entry.as_ref())
+      .expect("dangling handle")
+      .object
+  }
+
+  pub fn get_mut(&mut self, handle: Handle) -> &mut Object {
+    &mut self
+      .entries
+      .get_mut(handle.0)
+      .and_then(|entry| entry.as_mut())
+      .expect("dangling handle")
+      .object
+  }
+
+  pub fn string(&self, handle: Handle) -> &str {
+    match self.get(handle) {
+      Object::String(string) => string,
+      _ => panic!("expected string object"),
+    }
+  }
+
+  pub fn list(&self, handle: Handle) -> &Vec<Value> {
+    match self.get(handle) {
+      Object::List(list) => list,
+      _ => panic!("expected list object"),
+    }
+  }
+
+  pub fn list_mut(&mut self, handle: Handle) -> &mut Vec<Value> {
+    match self.get_mut(handle) {
+      Object::List(list) => list,
+      _ => panic!("expected list object"),
+    }
+  }
+
+  pub fn map(&self, handle: Handle) -> &BTreeMap<Key, Value> {
+    match self.get(handle) {
+      Object::Map(map) => map,
+      _ => panic!("expected map object"),
+    }
+  }
+
+  pub fn map_mut(&mut self, handle: Handle) -> &mut BTreeMap<Key, Value> {
+    match self.get_mut(handle) {
+      Object::Map(map) => map,
+      _ => panic!("expected map object"),
+    }
+  }
+
+  pub fn record(&self, handle: Handle) -> &Record {
+    match self.get(handle) {
+      Object::Record(record) => record,
+      _ => panic!("expected record object"),
+    }
+  }
+
+  pub fn record_mut(&mut self, handle: Handle) -> &mut Record {
+    match self.get_mut(handle) {
+      Object::Record(record) => record,
+      _ => panic!("expected record object"),
+    }
+  }
+
+  pub fn closure(&self, handle: Handle) -> &Closure {
+    match self.get(handle) {
+      Object::Closure(closure) => closure,
+      _ => panic!("expected closure object"),
+    }
+  }
+
+  pub fn upvalue(&self, handle: Handle) -> &Upvalue {
+    match self.get(handle) {
+      Object::Upvalue(upvalue) => upvalue,
+      _ => panic!("expected upvalue object"),
+    }
+  }
+
+  pub fn upvalue_mut(&mut self, handle: Handle) -> &mut Upvalue {
+    match self.get_mut(handle) {
+      Object::Upvalue(upvalue) => upvalue,
+      _ => panic!("expected upvalue object"),
+    }
+  }
+
+  // Allocates the object, collecting garbage first if the heap has grown past
+  // its threshold. The object being allocated is treated as a root, since the
+  // values it refers to have usually been popped off the stack already.
+  pub fn allocate<I>(&mut self, object: Object, roots: I) -> Handle
+  where
+    I: IntoIterator<Item = Handle>,
+  {
+    let size = object.size();
+
+    if self.is_stress || self.stats.bytes + size > self.threshold {
+      let mut gray = Vec::new();
+      object.trace(&mut gray);
+      self.collect_from(roots, gray);
+    }
+
+    self.stats.allocated += 1;
+    self.stats.live += 1;
+    self.stats.bytes += size;
+
+    let entry = Entry {
+      object,
+      size,
+      is_marked: false,
+    };
+    match self.free.pop() {
+      Some(index) => {
+        self.entries[index] = Some(entry);
+        Handle(index)
+      }
+      None => {
+        self.entries.push(Some(entry));
+        Handle(self.entries.len() - 1)
+      }
+    }
+  }
+
+  // Recounts the size of an object that has been mutated in place, so that
+  // lists, maps and records count towards the heap as they grow.
+  pub fn resize(&mut self, handle: Handle) {
+    let entry = self
+      .entries
+      .get_mut(handle.0)
+      .and_then(|entry| entry.as_mut())
+      .expect("dangling handle");
+    let size = entry.object.size();
+    self.stats.bytes = self.stats.bytes - entry.size + size;
+    entry.size = size;
+  }
+
+  pub fn collect<I>(&mut self, roots: I)
+  where
+    I: IntoIterator<Item = Handle>,
+  {
+    self.collect_from(roots, Vec::new());
+  }
+
+  fn collect_from<I>(&mut self, roots: I, mut gray: Vec<Handle>)
+  where
+    I: IntoIterator<Item = Handle>,
+  {
+    gray.extend(roots);
+
+    while let Some(handle) = gray.pop() {
+      let entry = self.entries[handle.0].as_mut().expect("dangling handle");
+      if entry.is_marked {
+        continue;
+      }
+      entry.is_marked = true;
+      entry.object.trace(&mut gray);
+    }
+
+    for (index, slot) in self.entries.iter_mut().enumerate() {
+      match slot {
+        Some(entry) if entry.is_marked => entry.is_marked = false,
+        Some(entry) => {
+          self.stats.freed += 1;
+          self.stats.live -= 1;
+          self.stats.bytes -= entry.size;
+          *slot = None;
+          self.free.push(index);
+        }
+        None => {}
+      }
+    }
+
+    self.stats.collections += 1;
+    self.threshold = (self.stats.bytes * GROWTH_FACTOR).max(INITIAL_THRESHOLD);
+  }
+}
+
+impl Default for Heap {
+  fn default() -> Heap {
+    Heap::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use crate::value::{Key, Value};
+
+  use super::{Closure, Heap, Object, Upvalue};
+
+  #[test]
+  fn collect() {
+    let mut heap = Heap::new();
+
+    let foo = heap.allocate(Object::String("foo".to_string()), []);
+    heap.allocate(Object::String("bar".to_string()), []);
+
+    heap.collect([foo]);
+
+    let stats = heap.stats();
+    assert_eq!(stats.collections, 1);
+    assert_eq!(stats.allocated, 2);
+    assert_eq!(stats.freed, 1);
+    assert_eq!(stats.live, 1);
+    assert_eq!(heap.string(foo), "foo");
+  }
+
+  #[test]
+  fn stress() {
+    let mut heap = Heap::new();
+    heap.set_stress(true);
+
+    heap.allocate(Object::String("foo".to_string()), []);
+    let bar = heap.allocate(Object::String("bar".to_string()), []);
+    heap.allocate(Object::String("baz".to_string()), [bar]);
+
+    let stats = heap.stats();
+    assert_eq!(stats.collections, 3);
+    assert_eq!(stats.freed, 1);
+    assert_eq!(heap.string(bar), "bar");
+  }
+
+  #[test]
+  fn trace() {
+    let mut heap = Heap::new();
+
+    let foo = heap.allocate(Object::String("foo".to_string()), []);
+    let upvalue =
+      heap.allocate(Object::Upvalue(Upvalue::Closed(Value::String(foo))), []);
+    let closure = heap.allocate(
+      Object::Closure(Closure {
+        function: 0,
+        upvalues: vec![upvalue],
+      }),
+      [],
+    );
+
+    heap.collect([closure]);
+
+    assert_eq!(heap.stats().live, 3);
+    assert_eq!(heap.string(foo), "foo");
+  }
+
+  #[test]
+  fn collect_grown() {
+    let mut heap = Heap::new();
+
+    let map = heap.allocate(Object::Map(BTreeMap::new()), []);
+    for int in 0..1000 {
+      heap.map_mut(map).insert(Key::Int(int), Value::Int(int));
+    }
+    let bytes = heap.stats().bytes;
+    heap.resize(map);
+    assert!(heap.stats().bytes > bytes);
+
+    heap.collect([]);
+
+    let stats = heap.stats();
+    assert_eq!(stats.live, 0);
+    assert_eq!(stats.bytes, 0);
+  }
+}
@@ -2,20 +2,51 @@ use std::fmt;
 
 use num_derive::FromPrimitive;
 
+// Operands are encoded as unsigned LEB128, seven bits to a byte starting with
+// the lowest, with the high bit set on every byte but the last.
+pub const MAX_OPERAND_LEN: usize = 10;
+
+// Jumps are usually emitted before their target is known, so their operands
+// are padded to a fixed width that can be patched in place later on.
+pub const JUMP_OPERAND_LEN: usize = 4;
+
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, FromPrimitive, PartialEq)]
 pub enum Instruction {
   PushConstant = 0,
   PushLocal,
+  SetLocal,
+  PushFunction,
   PushUnit,
+  MakeClosure,
+  GetUpvalue,
+  SetUpvalue,
+  MakeList,
+  MakeMap,
+  MakeRecord,
+  Index,
+  SetIndex,
+  Remove,
+  Keys,
+  GetField,
+  SetField,
   Pop,
+  PopN,
+  Print,
   Jump,
   JumpIf,
   Add,
   Subtract,
   Multiply,
   Divide,
+  Remainder,
   Negate,
+  BitAnd,
+  BitOr,
+  BitXor,
+  BitNot,
+  ShiftLeft,
+  ShiftRight,
   Greater,
   GreaterEqual,
   Less,
@@ -23,9 +54,38 @@ pub enum Instruction {
   Equal,
   NotEqual,
   Not,
-  And,
-  Or,
+  Length,
+  Call,
+  CallNative,
+  Invoke,
   Return,
+  Throw,
+}
+
+impl Instruction {
+  // The number of operands that follow the instruction.
+  pub fn operands(&self) -> usize {
+    match self {
+      Instruction::PushConstant
+      | Instruction::PushLocal
+      | Instruction::SetLocal
+      | Instruction::PushFunction
+      | Instruction::MakeClosure
+      | Instruction::GetUpvalue
+      | Instruction::SetUpvalue
+      | Instruction::MakeList
+      | Instruction::MakeMap
+      | Instruction::MakeRecord
+      | Instruction::GetField
+      | Instruction::SetField
+      | Instruction::PopN
+      | Instruction::Jump
+      | Instruction::JumpIf
+      | Instruction::Call => 1,
+      Instruction::CallNative | Instruction::Invoke => 2,
+      _ => 0,
+    }
+  }
 }
 
 impl fmt::Display for Instruction {
@@ -33,15 +93,38 @@ impl fmt::Display for Instruction {
     match self {
       Instruction::PushConstant => write!(f, "PushConstant"),
       Instruction::PushLocal => write!(f, "PushLocal"),
+      Instruction::SetLocal => write!(f, "SetLocal"),
+      Instruction::PushFunction => write!(f, "PushFunction"),
       Instruction::PushUnit => write!(f, "PushUnit"),
+      Instruction::MakeClosure => write!(f, "MakeClosure"),
+      Instruction::GetUpvalue => write!(f, "GetUpvalue"),
+      Instruction::SetUpvalue => write!(f, "SetUpvalue"),
+      Instruction::MakeList => write!(f, "MakeList"),
+      Instruction::MakeMap => write!(f, "MakeMap"),
+      Instruction::MakeRecord => write!(f, "MakeRecord"),
+      Instruction::Index => write!(f, "Index"),
+      Instruction::SetIndex => write!(f, "SetIndex"),
+      Instruction::Remove => write!(f, "Remove"),
+      Instruction::Keys => write!(f, "Keys"),
+      Instruction::GetField => write!(f, "GetField"),
+      Instruction::SetField => write!(f, "SetField"),
       Instruction::Pop => write!(f, "Pop"),
+      Instruction::PopN => write!(f, "PopN"),
+      Instruction::Print => write!(f, "Print"),
       Instruction::Jump => write!(f, "Jump"),
       Instruction::JumpIf => write!(f, "JumpIf"),
       Instruction::Add => write!(f, "Add"),
       Instruction::Subtract => write!(f, "Subtract"),
       Instruction::Multiply => write!(f, "Multiply"),
       Instruction::Divide => write!(f, "Divide"),
+      Instruction::Remainder => write!(f, "Remainder"),
       Instruction::Negate => write!(f, "Negate"),
+      Instruction::BitAnd => write!(f, "BitAnd"),
+      Instruction::BitOr => write!(f, "BitOr"),
+      Instruction::BitXor => write!(f, "BitXor"),
+      Instruction::BitNot => write!(f, "BitNot"),
+      Instruction::ShiftLeft => write!(f, "ShiftLeft"),
+      Instruction::ShiftRight => write!(f, "ShiftRight"),
       Instruction::Greater => write!(f, "Greater"),
       Instruction::GreaterEqual => write!(f, "GreaterEqual"),
       Instruction::Less => write!(f, "Less"),
@@ -49,9 +132,86 @@ impl fmt::Display for Instruction {
       Instruction::Equal => write!(f, "Equal"),
       Instruction::NotEqual => write!(f, "NotEqual"),
       Instruction::Not => write!(f, "Not"),
-      Instruction::And => write!(f, "And"),
-      Instruction::Or => write!(f, "Or"),
+      Instruction::Length => write!(f, "Length"),
+      Instruction::Call => write!(f, "Call"),
+      Instruction::CallNative => write!(f, "CallNative"),
+      Instruction::Invoke => write!(f, "Invoke"),
       Instruction::Return => write!(f, "Return"),
+      Instruction::Throw => write!(f, "Throw"),
+    }
+  }
+}
+
+pub fn encode_operand(operand: u64, bytes: &mut Vec<u8>) {
+  let mut operand = operand;
+  while operand >= 0x80 {
+    bytes.push(operand as u8 | 0x80);
+    operand >>= 7;
+  }
+  bytes.push(operand as u8);
+}
+
+// Encodes the operand in exactly `N` bytes, returning None if it does not fit.
+pub fn encode_padded_operand<const N: usize>(operand: u64) -> Option<[u8; N]> {
+  if N == 0 || (7 * N < 64 && operand >> (7 * N) != 0) {
+    return None;
+  }
+  let mut bytes = [0u8; N];
+  for (index, byte) in bytes.iter_mut().enumerate() {
+    *byte = (operand >> (7 * index)) as u8 & 0x7f;
+    if index + 1 < N {
+      *byte |= 0x80;
     }
   }
+  Some(bytes)
+}
+
+// Decodes the operand at the start of the bytes, returning it along with the
+// number of bytes it took up, or None if it is truncated or overflows a u64.
+pub fn decode_operand(bytes: &[u8]) -> Option<(u64, usize)> {
+  let mut operand = 0u64;
+  for (index, &byte) in bytes.iter().take(MAX_OPERAND_LEN).enumerate() {
+    let bits = (byte & 0x7f) as u64;
+    if index == MAX_OPERAND_LEN - 1 && bits > 1 {
+      return None;
+    }
+    operand |= bits << (7 * index);
+    if byte & 0x80 == 0 {
+      return Some((operand, index + 1));
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{decode_operand, encode_operand, encode_padded_operand};
+
+  #[test]
+  fn operand() {
+    for operand in [0, 1, 127, 128, 300, 1 << 35, u64::MAX] {
+      let mut bytes = Vec::new();
+      encode_operand(operand, &mut bytes);
+      assert_eq!(decode_operand(&bytes), Some((operand, bytes.len())));
+    }
+
+    let mut bytes = Vec::new();
+    encode_operand(127, &mut bytes);
+    assert_eq!(bytes, vec![0x7f]);
+    encode_operand(128, &mut bytes);
+    assert_eq!(bytes, vec![0x7f, 0x80, 0x01]);
+
+    assert_eq!(decode_operand(&[0x80, 0x80]), None);
+    assert_eq!(decode_operand(&[0xff; 10]), None);
+  }
+
+  #[test]
+  fn padded_operand() {
+    let bytes = encode_padded_operand::<4>(300).unwrap();
+    assert_eq!(bytes, [0xac, 0x82, 0x80, 0x00]);
+    assert_eq!(decode_operand(&bytes), Some((300, 4)));
+
+    assert_eq!(encode_padded_operand::<1>(128), None);
+    assert_eq!(encode_padded_operand::<4>(1 << 28), None);
+  }
 }
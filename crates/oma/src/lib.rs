@@ -1,4 +1,7 @@
+pub mod assembly;
 pub mod executable;
+pub mod heap;
 pub mod instruction;
 pub mod machine;
 pub mod value;
+pub mod verify;
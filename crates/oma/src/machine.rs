@@ -1,11 +1,20 @@
 use std::{
   cmp::{PartialEq, PartialOrd},
+  collections::{BTreeMap, HashMap, HashSet},
+  convert::TryFrom,
+  io::{self, Write},
+  iter, mem,
   ops::{Add, Div, Mul, Sub},
+  rc::Rc,
 };
 
-use num_traits::FromPrimitive;
-
-use crate::{executable::Chunk, instruction::Instruction, value::Value};
+use crate::{
+  executable::{Chunk, Constant, Executable, Location},
+  heap::{Closure, Handle, Heap, Object, Record, Stats, Upvalue},
+  instruction::Instruction,
+  value::{Key, Value},
+  verify::{self, Decoded},
+};
 
 #[derive(Debug)]
 pub enum Error {
@@ -13,30 +22,391 @@ pub enum Error {
   InvalidInstruction(u8),
   InvalidConstant(u64),
   InvalidLocal(u64),
+  InvalidFunction(u64),
+  InvalidUpvalue(u64),
+  InvalidStruct(u64),
+  InvalidNative(String),
+  InvalidArity(usize, usize),
   InvalidType,
+  IndexOutOfBounds(i64),
+  UnhashableKey,
+  MissingKey,
+  MissingField,
+  MissingMethod,
   EmptyStack,
+  DivisionByZero,
+  IntegerOverflow,
+  OutOfFuel,
+  StackOverflow,
+  FrameOverflow,
+  OutOfMemory,
+  Native(String),
+  Io(io::Error),
+  Verify(usize, verify::Error),
+  // A value was thrown that no handler caught.
+  Uncaught(Value),
+}
+
+// An error along with the frames that were active when it occurred, innermost
+// first.
+#[derive(Debug)]
+pub struct Trace {
+  pub error: Error,
+  pub frames: Vec<TraceFrame>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+  pub chunk: usize,
+  pub name: String,
+  // The offset of the instruction that failed, or of the call the frame was
+  // waiting on.
+  pub offset: usize,
+  pub location: Option<Location>,
+}
+
+// A function provided by the host, which is called with the machine and the
+// arguments it was given.
+pub type NativeFn = dyn Fn(&mut Machine, &[Value]) -> Result<Value, Error>;
+
+#[derive(Clone)]
+struct Native {
+  arity: usize,
+  function: Rc<NativeFn>,
 }
 
 pub struct Machine {
-  current: usize,
+  frame: Frame,
+  frames: Vec<Frame>,
   stack: Vec<Value>,
+  open_upvalues: Vec<Handle>,
+  heap: Heap,
+  natives: HashMap<String, Native>,
+  output: Box<dyn Write>,
+  breakpoints: HashSet<(usize, usize)>,
+  // The instructions of each chunk by offset, decoded once the executable has
+  // been verified so that they are not decoded again each time they run.
+  program: Vec<Vec<Option<Decoded>>>,
+  // The depth of the stack each handler of each chunk unwinds to, relative to
+  // the base of the frame.
+  handlers: Vec<Vec<Option<usize>>>,
+  // Limits on the resources a program can use, which are unlimited when None.
+  // The fuel is the number of instructions left to execute.
+  fuel: Option<u64>,
+  max_stack: Option<usize>,
+  max_frames: Option<usize>,
+  max_heap: Option<usize>,
+}
+
+// Slot 0 of a frame holds the callee, followed by the arguments and then the
+// remaining locals of the function.
+#[derive(Clone, Debug)]
+pub struct Frame {
+  chunk: usize,
+  current: usize,
+  // The offset of the instruction being executed, which stays on the call
+  // while a callee runs.
+  start: usize,
+  base: usize,
+}
+
+impl Frame {
+  pub fn chunk(&self) -> usize {
+    self.chunk
+  }
+
+  // The offset of the next instruction to be executed within the chunk.
+  pub fn offset(&self) -> usize {
+    self.current
+  }
+
+  pub fn base(&self) -> usize {
+    self.base
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+  Running,
+  Breakpoint,
+  Finished(Value),
 }
 
 impl Machine {
   pub fn new() -> Machine {
     Machine {
-      current: 0,
+      frame: Frame {
+        chunk: 0,
+        current: 0,
+        start: 0,
+        base: 0,
+      },
+      frames: Vec::new(),
       stack: Vec::with_capacity(32),
+      open_upvalues: Vec::new(),
+      heap: Heap::new(),
+      natives: HashMap::new(),
+      output: Box::new(io::stdout()),
+      breakpoints: HashSet::new(),
+      program: Vec::new(),
+      handlers: Vec::new(),
+      fuel: None,
+      max_stack: None,
+      max_frames: None,
+      max_heap: None,
+    }
+  }
+
+  // Registers a native function under the given name, replacing any native
+  // previously registered under it. Paths such as `io::print` are valid names.
+  pub fn register_native<F>(&mut self, name: &str, arity: usize, function: F)
+  where
+    F: Fn(&mut Machine, &[Value]) -> Result<Value, Error> + 'static,
+  {
+    self.natives.insert(
+      name.to_string(),
+      Native {
+        arity,
+        function: Rc::new(function),
+      },
+    );
+  }
+
+  pub fn natives(&self) -> impl Iterator<Item = &str> {
+    self.natives.keys().map(String::as_str)
+  }
+
+  // Sets the sink that `Print` writes to, which is standard output by default.
+  pub fn set_output<W>(&mut self, output: W)
+  where
+    W: Write + 'static,
+  {
+    self.output = Box::new(output);
+  }
+
+  pub fn heap(&self) -> &Heap {
+    &self.heap
+  }
+
+  pub fn gc_stats(&self) -> Stats {
+    self.heap.stats()
+  }
+
+  pub fn set_gc_stress(&mut self, is_stress: bool) {
+    self.heap.set_stress(is_stress);
+  }
+
+  pub fn collect_garbage(&mut self) {
+    let roots = self.stack.iter().filter_map(Value::handle);
+    self
+      .heap
+      .collect(roots.chain(self.open_upvalues.iter().copied()));
+  }
+
+  // Each instruction executed uses up one unit of fuel. A machine that ran out
+  // of fuel can be given more and resumed where it stopped.
+  pub fn set_fuel(&mut self, fuel: Option<u64>) {
+    self.fuel = fuel;
+  }
+
+  pub fn add_fuel(&mut self, fuel: u64) {
+    if let Some(current) = &mut self.fuel {
+      *current = current.saturating_add(fuel);
+    }
+  }
+
+  pub fn fuel(&self) -> Option<u64> {
+    self.fuel
+  }
+
+  // The maximum number of values on the stack, across all frames.
+  pub fn set_max_stack(&mut self, max_stack: Option<usize>) {
+    self.max_stack = max_stack;
+  }
+
+  // The maximum number of frames, including the frame of the entry function.
+  pub fn set_max_frames(&mut self, max_frames: Option<usize>) {
+    self.max_frames = max_frames;
+  }
+
+  // The maximum number of bytes allocated on the heap. Garbage is collected
+  // before giving up once the heap grows past it.
+  pub fn set_max_heap(&mut self, max_heap: Option<usize>) {
+    self.max_heap = max_heap;
+  }
+
+  // Breakpoints are set on the offset of an instruction within a chunk, and
+  // are only checked by `resume`.
+  pub fn add_breakpoint(&mut self, chunk: usize, offset: usize) {
+    self.breakpoints.insert((chunk, offset));
+  }
+
+  pub fn remove_breakpoint(&mut self, chunk: usize, offset: usize) -> bool {
+    self.breakpoints.remove(&(chunk, offset))
+  }
+
+  pub fn breakpoints(&self) -> impl Iterator<Item = &(usize, usize)> {
+    self.breakpoints.iter()
+  }
+
+  pub fn stack(&self) -> &[Value] {
+    &self.stack
+  }
+
+  // Returns the call frames from the outermost to the one being executed.
+  pub fn frames(&self) -> impl Iterator<Item = &Frame> {
+    self.frames.iter().chain(iter::once(&self.frame))
+  }
+
+  // The source location of the instruction executed last, which is the one
+  // that failed if `step` returned an error.
+  pub fn location(&self, executable: &Executable) -> Option<Location> {
+    executable
+      .chunk(self.frame.chunk)?
+      .location(self.frame.start)
+  }
+
+  // Describes each active frame, innermost first.
+  pub fn trace(&self, executable: &Executable) -> Vec<TraceFrame> {
+    iter::once(&self.frame)
+      .chain(self.frames.iter().rev())
+      .map(|frame| {
+        let chunk = executable.chunk(frame.chunk);
+        TraceFrame {
+          chunk: frame.chunk,
+          name: chunk
+            .map(|chunk| chunk.name().to_string())
+            .unwrap_or_default(),
+          offset: frame.start,
+          location: chunk.and_then(|chunk| chunk.location(frame.start)),
+        }
+      })
+      .collect()
+  }
+
+  pub fn execute(&mut self, executable: &Executable) -> Result<Value, Trace> {
+    self.start(executable).map_err(|error| Trace {
+      error,
+      frames: Vec::new(),
+    })?;
+    loop {
+      if let Status::Finished(result) = self.step(executable)? {
+        return Ok(result);
+      }
+    }
+  }
+
+  // Verifies the executable and prepares the machine to execute its entry
+  // function, one instruction at a time with `step` or until a breakpoint with
+  // `resume`.
+  pub fn start(&mut self, executable: &Executable) -> Result<(), Error> {
+    let entry = executable.entry();
+    if executable.chunk(entry).is_none() {
+      return Err(Error::InvalidFunction(entry as u64));
+    }
+
+    self.program = Vec::new();
+    self.handlers = Vec::new();
+    for (index, chunk) in executable.chunks().iter().enumerate() {
+      let analysis = verify::analyze(executable, index)
+        .map_err(|error| Error::Verify(index, error))?;
+      let mut program = vec![None; chunk.code().len()];
+      for (offset, decoded) in analysis.instructions {
+        program[offset] = Some(decoded);
+      }
+      self.program.push(program);
+      self.handlers.push(analysis.handlers);
+    }
+
+    self.frames = Vec::new();
+    self.stack = Vec::new();
+    self.open_upvalues = Vec::new();
+
+    self.push(Value::Function(entry));
+    self.frame = Frame {
+      chunk: entry,
+      current: 0,
+      start: 0,
+      base: 0,
+    };
+
+    Ok(())
+  }
+
+  // Executes instructions until the executable finishes or the next
+  // instruction is at a breakpoint. At least one instruction is executed, so
+  // that resuming from a breakpoint does not stop at it again.
+  pub fn resume(&mut self, executable: &Executable) -> Result<Status, Trace> {
+    loop {
+      if let Status::Finished(result) = self.step(executable)? {
+        return Ok(Status::Finished(result));
+      }
+      if self
+        .breakpoints
+        .contains(&(self.frame.chunk, self.frame.current))
+      {
+        return Ok(Status::Breakpoint);
+      }
+    }
+  }
+
+  // Fuel is checked before the instruction is executed, so that the machine can
+  // be resumed after running out. The stack and heap are checked afterwards,
+  // which lets a single instruction go past their limits.
+  pub fn step(&mut self, executable: &Executable) -> Result<Status, Trace> {
+    self.frame.start = self.frame.current;
+    match self.fuel {
+      Some(0) => {
+        return Err(Trace {
+          error: Error::OutOfFuel,
+          frames: self.trace(executable),
+        })
+      }
+      Some(fuel) => self.fuel = Some(fuel - 1),
+      None => {}
+    }
+    self
+      .instruction(executable)
+      .and_then(|status| self.check_limits().map(|_| status))
+      .map_err(|error| Trace {
+        error,
+        frames: self.trace(executable),
+      })
+  }
+
+  fn check_limits(&mut self) -> Result<(), Error> {
+    if let Some(max_stack) = self.max_stack {
+      if self.stack.len() > max_stack {
+        return Err(Error::StackOverflow);
+      }
+    }
+    if let Some(max_heap) = self.max_heap {
+      if self.heap.stats().bytes > max_heap {
+        self.collect_garbage();
+        if self.heap.stats().bytes > max_heap {
+          return Err(Error::OutOfMemory);
+        }
+      }
     }
+    Ok(())
   }
 
-  pub fn execute(&mut self, chunk: &Chunk) -> Result<Value, Error> {
+  fn instruction(&mut self, executable: &Executable) -> Result<Status, Error> {
+    // Integer arithmetic is checked, so that it behaves the same regardless of
+    // how the machine was built.
     macro_rules! arithmetic {
-      ($op:ident) => {
+      (
+        $op:ident,
+        $checked:ident
+        $(, $pattern:pat => $result:expr)* $(,)?
+      ) => {
         let right = self.pop()?;
         let left = self.pop()?;
         let result = match (left, right) {
-          (Value::Int(left), Value::Int(right)) => Value::Int(left.$op(right)),
+          $($pattern => $result,)*
+          (Value::Int(left), Value::Int(right)) => Value::Int(
+            left.$checked(right).ok_or(Error::IntegerOverflow)?,
+          ),
           (Value::Float(left), Value::Int(right)) => {
             Value::Float(left.$op(right as f64))
           }
@@ -52,6 +422,19 @@ impl Machine {
       };
     }
 
+    // Operations on integers only, which are bound to the given names.
+    macro_rules! integer {
+      ($left:ident, $right:ident => $result:expr) => {
+        let right = self.pop()?;
+        let left = self.pop()?;
+        let result = match (left, right) {
+          (Value::Int($left), Value::Int($right)) => Value::Int($result),
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(result);
+      };
+    }
+
     macro_rules! equality {
       ($op:ident) => {
         let right = self.pop()?;
@@ -72,6 +455,9 @@ impl Machine {
           (Value::Bool(left), Value::Bool(right)) => {
             Value::Bool(left.$op(&right))
           }
+          (Value::String(left), Value::String(right)) => {
+            Value::Bool(self.heap.string(left).$op(self.heap.string(right)))
+          }
           _ => return Err(Error::InvalidType),
         };
         self.push(result);
@@ -95,175 +481,726 @@ impl Machine {
           (Value::Float(left), Value::Float(right)) => {
             Value::Bool(left.$op(&right))
           }
+          (Value::String(left), Value::String(right)) => {
+            Value::Bool(self.heap.string(left).$op(self.heap.string(right)))
+          }
           _ => return Err(Error::InvalidType),
         };
         self.push(result);
       };
     }
 
-    self.current = 0;
-    self.stack = Vec::new();
+    let chunk = match executable.chunk(self.frame.chunk) {
+      Some(chunk) => chunk,
+      None => return Err(Error::InvalidFunction(self.frame.chunk as u64)),
+    };
 
-    loop {
-      let byte = self.advance(chunk)?;
-      let instruction =
-        Instruction::from_u8(byte).ok_or(Error::InvalidInstruction(byte))?;
-
-      match instruction {
-        Instruction::PushConstant => {
-          let index = self.advance_u64(chunk)?;
-          let constant = chunk
-            .constant(index as usize)
-            .ok_or(Error::InvalidConstant(index))?;
-          self.push(constant.into());
-        }
-        Instruction::PushLocal => {
-          let index = self.advance_u64(chunk)?;
-          let local = self
-            .stack
-            .get(index as usize)
-            .cloned()
-            .ok_or(Error::InvalidLocal(index))?;
-          self.push(local);
-        }
-        Instruction::PushUnit => {
-          self.push(Value::Unit);
-        }
-        Instruction::Pop => {
-          println!("{}", self.pop()?);
-        }
-        Instruction::Jump => {
-          let offset = self.advance_u64(chunk)?;
-          self.current = offset as usize;
-        }
-        Instruction::JumpIf => {
-          let offset = self.advance_u64(chunk)?;
+    let decoded = self
+      .program
+      .get(self.frame.chunk)
+      .and_then(|program| program.get(self.frame.current));
+    let Decoded {
+      instruction,
+      operands,
+      next,
+    } = match decoded {
+      Some(Some(decoded)) => *decoded,
+      _ => return Err(Error::SegmentationFault(self.frame.current)),
+    };
+    self.frame.current = next;
 
-          if let Value::Bool(bool) = self.pop()? {
-            if bool {
-              self.current = offset as usize;
-            }
+    match instruction {
+      Instruction::PushConstant => {
+        let index = operands[0];
+        let constant = chunk
+          .constant(index as usize)
+          .ok_or(Error::InvalidConstant(index))?;
+        let value = self.load(constant);
+        self.push(value);
+      }
+      Instruction::PushLocal => {
+        let index = operands[0];
+        let local = self
+          .stack
+          .get(self.frame.base + index as usize)
+          .cloned()
+          .ok_or(Error::InvalidLocal(index))?;
+        self.push(local);
+      }
+      Instruction::SetLocal => {
+        let index = operands[0];
+        let value = self.stack.last().cloned().ok_or(Error::EmptyStack)?;
+        let local = self
+          .stack
+          .get_mut(self.frame.base + index as usize)
+          .ok_or(Error::InvalidLocal(index))?;
+        *local = value;
+      }
+      Instruction::PushFunction => {
+        let index = operands[0];
+        executable
+          .chunk(index as usize)
+          .ok_or(Error::InvalidFunction(index))?;
+        self.push(Value::Function(index as usize));
+      }
+      Instruction::PushUnit => {
+        self.push(Value::Unit);
+      }
+      Instruction::MakeClosure => {
+        let index = operands[0];
+        let function = executable
+          .chunk(index as usize)
+          .ok_or(Error::InvalidFunction(index))?;
+
+        let mut upvalues = Vec::new();
+        for capture in function.captures() {
+          let upvalue = if capture.is_local {
+            self.capture(self.frame.base + capture.index)
           } else {
-            return Err(Error::InvalidType);
-          }
-        }
-        Instruction::Add => {
-          arithmetic!(add);
-        }
-        Instruction::Subtract => {
-          arithmetic!(sub);
-        }
-        Instruction::Multiply => {
-          arithmetic!(mul);
-        }
-        Instruction::Divide => {
-          arithmetic!(div);
-        }
-        Instruction::Negate => {
-          let operand = self.pop()?;
-          let result = match operand {
-            Value::Int(int) => Value::Int(-int),
-            Value::Float(float) => Value::Float(-float),
-            _ => return Err(Error::InvalidType),
+            self.upvalue(capture.index as u64)?
           };
-          self.push(result);
-        }
-        Instruction::Greater => {
-          comparison!(gt);
+          upvalues.push(upvalue);
         }
-        Instruction::GreaterEqual => {
-          comparison!(ge);
-        }
-        Instruction::Less => {
-          comparison!(lt);
-        }
-        Instruction::LessEqual => {
-          comparison!(le);
+
+        let closure = self.allocate(Object::Closure(Closure {
+          function: index as usize,
+          upvalues,
+        }));
+        self.push(Value::Closure(closure));
+      }
+      Instruction::MakeList => {
+        let len = operands[0] as usize;
+        let start =
+          self.stack.len().checked_sub(len).ok_or(Error::EmptyStack)?;
+        let list = self.stack.split_off(start);
+        let list = self.allocate(Object::List(list));
+        self.push(Value::List(list));
+      }
+      Instruction::MakeMap => {
+        let len = operands[0] as usize;
+        let start = self
+          .stack
+          .len()
+          .checked_sub(len * 2)
+          .ok_or(Error::EmptyStack)?;
+
+        let mut map = BTreeMap::new();
+        for entry in self.stack[start..].chunks(2) {
+          let key = entry[0].key(&self.heap).ok_or(Error::UnhashableKey)?;
+          map.insert(key, entry[1].clone());
         }
-        Instruction::Equal => {
-          equality!(eq);
+
+        // The entries stay on the stack until the map has been allocated, so
+        // that they remain reachable in the meantime.
+        let map = self.allocate(Object::Map(map));
+        self.stack.truncate(start);
+        self.push(Value::Map(map));
+      }
+      Instruction::MakeRecord => {
+        let index = operands[0];
+        let structure = executable
+          .structure(index as usize)
+          .ok_or(Error::InvalidStruct(index))?;
+        let start = self
+          .stack
+          .len()
+          .checked_sub(structure.fields.len())
+          .ok_or(Error::EmptyStack)?;
+        let fields = self.stack.split_off(start);
+        let record = self.allocate(Object::Record(Record {
+          structure: index as usize,
+          fields,
+        }));
+        self.push(Value::Record(record));
+      }
+      Instruction::Index => {
+        let index = self.pop()?;
+        let receiver = self.pop()?;
+        let result = match (receiver, index) {
+          (Value::List(list), Value::Int(index)) => {
+            let list = self.heap.list(list);
+            element(list, index)?.clone()
+          }
+          (Value::Map(map), key) => {
+            let key = key.key(&self.heap).ok_or(Error::UnhashableKey)?;
+            let map = self.heap.map(map);
+            map.get(&key).cloned().ok_or(Error::MissingKey)?
+          }
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(result);
+      }
+      Instruction::SetIndex => {
+        let value = self.pop()?;
+        let index = self.pop()?;
+        let receiver = self.pop()?;
+        match (receiver, index) {
+          (Value::List(list), Value::Int(index)) => {
+            *element_mut(self.heap.list_mut(list), index)? = value.clone();
+            self.heap.resize(list);
+          }
+          (Value::Map(map), key) => {
+            let key = key.key(&self.heap).ok_or(Error::UnhashableKey)?;
+            self.heap.map_mut(map).insert(key, value.clone());
+            self.heap.resize(map);
+          }
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(value);
+      }
+      Instruction::Remove => {
+        let key = self.pop()?;
+        let receiver = self.pop()?;
+        let result = match receiver {
+          Value::Map(map) => {
+            let key = key.key(&self.heap).ok_or(Error::UnhashableKey)?;
+            let value = self.heap.map_mut(map).remove(&key);
+            self.heap.resize(map);
+            value.unwrap_or(Value::Unit)
+          }
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(result);
+      }
+      Instruction::Keys => {
+        let keys = match self.stack.last() {
+          Some(Value::Map(map)) => {
+            self.heap.map(*map).keys().cloned().collect::<Vec<Key>>()
+          }
+          Some(_) => return Err(Error::InvalidType),
+          None => return Err(Error::EmptyStack),
+        };
+
+        // Each key is pushed as soon as it is loaded, so that the keys that
+        // are strings stay reachable until the list has been allocated.
+        let start = self.stack.len();
+        for key in keys {
+          let key = self.load_key(key);
+          self.push(key);
         }
-        Instruction::NotEqual => {
-          equality!(ne);
+        let list = self.stack.split_off(start);
+        let list = self.allocate(Object::List(list));
+
+        self.pop()?;
+        self.push(Value::List(list));
+      }
+      Instruction::GetField => {
+        let index = operands[0];
+        let name = name(chunk, index)?;
+        let receiver = match self.pop()? {
+          Value::Record(record) => record,
+          _ => return Err(Error::InvalidType),
+        };
+        let record = self.heap.record(receiver);
+        let field = field(executable, record, name)?;
+        let value = record.fields[field].clone();
+        self.push(value);
+      }
+      Instruction::SetField => {
+        let index = operands[0];
+        let name = name(chunk, index)?;
+        let value = self.pop()?;
+        let receiver = match self.pop()? {
+          Value::Record(record) => record,
+          _ => return Err(Error::InvalidType),
+        };
+        let field = field(executable, self.heap.record(receiver), name)?;
+        self.heap.record_mut(receiver).fields[field] = value.clone();
+        self.heap.resize(receiver);
+        self.push(value);
+      }
+      Instruction::GetUpvalue => {
+        let index = operands[0];
+        let upvalue = self.upvalue(index)?;
+        let value = match self.heap.upvalue(upvalue) {
+          Upvalue::Open(slot) => self.stack[*slot].clone(),
+          Upvalue::Closed(value) => value.clone(),
+        };
+        self.push(value);
+      }
+      Instruction::SetUpvalue => {
+        let index = operands[0];
+        let upvalue = self.upvalue(index)?;
+        let value = self.stack.last().cloned().ok_or(Error::EmptyStack)?;
+        match self.heap.upvalue_mut(upvalue) {
+          Upvalue::Open(slot) => self.stack[*slot] = value,
+          Upvalue::Closed(closed) => *closed = value,
         }
-        Instruction::Not => {
-          let operand = self.pop()?;
-          let result = match operand {
-            Value::Bool(bool) => Value::Bool(!bool),
-            _ => return Err(Error::InvalidType),
-          };
-          self.push(result);
+      }
+      Instruction::Pop => {
+        self.pop()?;
+      }
+      // Pops the values beneath the one on top of the stack, closing any of
+      // them that have been captured.
+      Instruction::PopN => {
+        let len = operands[0] as usize;
+        let value = self.pop()?;
+        let start =
+          self.stack.len().checked_sub(len).ok_or(Error::EmptyStack)?;
+        self.close_upvalues(start);
+        self.stack.truncate(start);
+        self.push(value);
+      }
+      Instruction::Print => {
+        let value = self.pop()?;
+        writeln!(self.output, "{}", value.display(&self.heap))
+          .map_err(Error::Io)?;
+        self.push(Value::Unit);
+      }
+      Instruction::Jump => {
+        let offset = operands[0];
+        self.frame.current = offset as usize;
+      }
+      Instruction::JumpIf => {
+        let offset = operands[0];
+
+        if let Value::Bool(bool) = self.pop()? {
+          if bool {
+            self.frame.current = offset as usize;
+          }
+        } else {
+          return Err(Error::InvalidType);
         }
-        Instruction::And => {
-          let right = self.pop()?;
-          let left = self.pop()?;
-          let result = match (left, right) {
-            (Value::Bool(left), Value::Bool(right)) => {
-              Value::Bool(left && right)
-            }
-            _ => return Err(Error::InvalidType),
-          };
-          self.push(result);
+      }
+      Instruction::Add => {
+        arithmetic!(
+          add,
+          checked_add,
+          (Value::String(left), Value::String(right)) => {
+            let string = format!(
+              "{}{}",
+              self.heap.string(left),
+              self.heap.string(right)
+            );
+            Value::String(self.allocate(Object::String(string)))
+          },
+        );
+      }
+      Instruction::Subtract => {
+        arithmetic!(sub, checked_sub);
+      }
+      Instruction::Multiply => {
+        arithmetic!(mul, checked_mul);
+      }
+      Instruction::Divide => {
+        arithmetic!(
+          div,
+          checked_div,
+          (Value::Int(_), Value::Int(0)) => return Err(Error::DivisionByZero),
+        );
+      }
+      Instruction::Remainder => {
+        integer!(left, right => match right {
+          0 => return Err(Error::DivisionByZero),
+          _ => left.checked_rem(right).ok_or(Error::IntegerOverflow)?,
+        });
+      }
+      Instruction::Negate => {
+        let operand = self.pop()?;
+        let result = match operand {
+          Value::Int(int) => {
+            Value::Int(int.checked_neg().ok_or(Error::IntegerOverflow)?)
+          }
+          Value::Float(float) => Value::Float(-float),
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(result);
+      }
+      Instruction::BitAnd => {
+        integer!(left, right => left & right);
+      }
+      Instruction::BitOr => {
+        integer!(left, right => left | right);
+      }
+      Instruction::BitXor => {
+        integer!(left, right => left ^ right);
+      }
+      Instruction::BitNot => {
+        let result = match self.pop()? {
+          Value::Int(int) => Value::Int(!int),
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(result);
+      }
+      // Shifting by a negative amount or by the width of an integer or more
+      // overflows, rather than being masked as the host would.
+      Instruction::ShiftLeft => {
+        integer!(left, right => u32::try_from(right)
+          .ok()
+          .and_then(|right| left.checked_shl(right))
+          .ok_or(Error::IntegerOverflow)?);
+      }
+      Instruction::ShiftRight => {
+        integer!(left, right => u32::try_from(right)
+          .ok()
+          .and_then(|right| left.checked_shr(right))
+          .ok_or(Error::IntegerOverflow)?);
+      }
+      Instruction::Greater => {
+        comparison!(gt);
+      }
+      Instruction::GreaterEqual => {
+        comparison!(ge);
+      }
+      Instruction::Less => {
+        comparison!(lt);
+      }
+      Instruction::LessEqual => {
+        comparison!(le);
+      }
+      Instruction::Equal => {
+        equality!(eq);
+      }
+      Instruction::NotEqual => {
+        equality!(ne);
+      }
+      Instruction::Not => {
+        let operand = self.pop()?;
+        let result = match operand {
+          Value::Bool(bool) => Value::Bool(!bool),
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(result);
+      }
+      Instruction::Length => {
+        let operand = self.pop()?;
+        let result = match operand {
+          Value::String(handle) => {
+            Value::Int(self.heap.string(handle).chars().count() as i64)
+          }
+          Value::List(handle) => {
+            Value::Int(self.heap.list(handle).len() as i64)
+          }
+          Value::Map(handle) => Value::Int(self.heap.map(handle).len() as i64),
+          _ => return Err(Error::InvalidType),
+        };
+        self.push(result);
+      }
+      Instruction::Call => {
+        let arity = operands[0] as usize;
+        self.call(executable, arity)?;
+      }
+      Instruction::CallNative => {
+        let index = operands[0];
+        let arity = operands[1] as usize;
+        let name = name(chunk, index)?;
+        self.call_native(name, arity)?;
+      }
+      Instruction::Invoke => {
+        let index = operands[0];
+        let arity = operands[1] as usize;
+        let name = name(chunk, index)?;
+        self.invoke(executable, name, arity)?;
+      }
+      Instruction::Return => {
+        let result = self.pop()?;
+        self.close_upvalues(self.frame.base);
+        self.stack.truncate(self.frame.base);
+        match self.frames.pop() {
+          Some(frame) => {
+            self.frame = frame;
+            self.push(result);
+          }
+          None => return Ok(Status::Finished(result)),
         }
-        Instruction::Or => {
-          let right = self.pop()?;
-          let left = self.pop()?;
-          let result = match (left, right) {
-            (Value::Bool(left), Value::Bool(right)) => {
-              Value::Bool(left || right)
+      }
+      Instruction::Throw => {
+        let value = self.pop()?;
+        self.throw(executable, value)?;
+      }
+    };
+
+    Ok(Status::Running)
+  }
+
+  // Unwinds to the innermost handler whose range covers the instruction a
+  // frame is executing, closing the upvalues of the values it discards. If no
+  // frame has such a handler, the frames are left as they are so that the trace
+  // shows where the value was thrown.
+  fn throw(
+    &mut self,
+    executable: &Executable,
+    value: Value,
+  ) -> Result<(), Error> {
+    let handlers = &self.handlers;
+    let found = iter::once(&self.frame)
+      .chain(self.frames.iter().rev())
+      .enumerate()
+      .find_map(|(level, frame)| {
+        let chunk = executable.chunk(frame.chunk)?;
+        let depths = handlers.get(frame.chunk)?;
+        chunk
+          .handlers()
+          .iter()
+          .zip(depths)
+          .find_map(|(handler, depth)| match depth {
+            Some(depth)
+              if handler.start <= frame.start && frame.start < handler.end =>
+            {
+              Some((level, handler.target, frame.base + depth))
             }
-            _ => return Err(Error::InvalidType),
-          };
-          self.push(result);
-        }
-        Instruction::Return => {
-          return self.pop();
+            _ => None,
+          })
+      });
+    let (level, target, depth) = match found {
+      Some(found) => found,
+      None => return Err(Error::Uncaught(value)),
+    };
+
+    for _ in 0..level {
+      self.frame = self.frames.pop().expect("missing frame");
+    }
+    self.close_upvalues(depth);
+    self.stack.truncate(depth);
+    self.push(value);
+    self.frame.current = target;
+
+    Ok(())
+  }
+
+  fn call(
+    &mut self,
+    executable: &Executable,
+    arity: usize,
+  ) -> Result<(), Error> {
+    let base = self
+      .stack
+      .len()
+      .checked_sub(arity + 1)
+      .ok_or(Error::EmptyStack)?;
+
+    let index = match self.stack[base] {
+      Value::Function(index) => index,
+      Value::Closure(handle) => self.heap.closure(handle).function,
+      _ => return Err(Error::InvalidType),
+    };
+
+    self.enter(executable, index, arity, base)
+  }
+
+  // The arguments stay on the stack while the native runs, so that they remain
+  // reachable if it allocates.
+  fn call_native(&mut self, name: &str, arity: usize) -> Result<(), Error> {
+    let native = self
+      .natives
+      .get(name)
+      .cloned()
+      .ok_or_else(|| Error::InvalidNative(name.to_string()))?;
+    if native.arity != arity {
+      return Err(Error::InvalidArity(native.arity, arity));
+    }
+
+    let start = self
+      .stack
+      .len()
+      .checked_sub(arity)
+      .ok_or(Error::EmptyStack)?;
+    let arguments = self.stack[start..].to_vec();
+    let result = (native.function)(self, &arguments)?;
+    self.stack.truncate(start);
+    self.push(result);
+
+    Ok(())
+  }
+
+  // Calls the method of the receiver's struct, which takes the receiver in
+  // slot 0 in place of the callee. If the struct has no such method, the value
+  // of the field of the same name is called instead.
+  fn invoke(
+    &mut self,
+    executable: &Executable,
+    name: &str,
+    arity: usize,
+  ) -> Result<(), Error> {
+    let base = self
+      .stack
+      .len()
+      .checked_sub(arity + 1)
+      .ok_or(Error::EmptyStack)?;
+
+    let record = match self.stack[base] {
+      Value::Record(handle) => self.heap.record(handle),
+      _ => return Err(Error::InvalidType),
+    };
+    let structure = executable
+      .structure(record.structure)
+      .ok_or(Error::InvalidStruct(record.structure as u64))?;
+
+    if let Some(method) = structure.method(name) {
+      return self.enter(executable, method, arity, base);
+    }
+
+    let field = structure.field(name).ok_or(Error::MissingMethod)?;
+    self.stack[base] = record.fields[field].clone();
+    self.call(executable, arity)
+  }
+
+  fn enter(
+    &mut self,
+    executable: &Executable,
+    index: usize,
+    arity: usize,
+    base: usize,
+  ) -> Result<(), Error> {
+    let chunk = executable
+      .chunk(index)
+      .ok_or(Error::InvalidFunction(index as u64))?;
+    if chunk.arity() != arity {
+      return Err(Error::InvalidArity(chunk.arity(), arity));
+    }
+    if let Some(max_frames) = self.max_frames {
+      if self.frames.len() + 1 >= max_frames {
+        return Err(Error::FrameOverflow);
+      }
+    }
+
+    let frame = Frame {
+      chunk: index,
+      current: 0,
+      start: 0,
+      base,
+    };
+    self.frames.push(mem::replace(&mut self.frame, frame));
+
+    Ok(())
+  }
+
+  // Returns the open upvalue for the stack slot, creating one if the slot has
+  // not been captured yet, so that closures capturing the same variable share
+  // it.
+  fn capture(&mut self, slot: usize) -> Handle {
+    let heap = &self.heap;
+    let existing = self.open_upvalues.iter().copied().find(|&upvalue| {
+      matches!(heap.upvalue(upvalue), Upvalue::Open(open) if *open == slot)
+    });
+    if let Some(upvalue) = existing {
+      return upvalue;
+    }
+
+    let upvalue = self.allocate(Object::Upvalue(Upvalue::Open(slot)));
+    self.open_upvalues.push(upvalue);
+    upvalue
+  }
+
+  fn close_upvalues(&mut self, base: usize) {
+    let heap = &mut self.heap;
+    let stack = &self.stack;
+    self.open_upvalues.retain(|&handle| {
+      let upvalue = heap.upvalue_mut(handle);
+      match *upvalue {
+        Upvalue::Open(slot) if slot >= base => {
+          *upvalue = Upvalue::Closed(stack[slot].clone());
+          false
         }
-      };
+        _ => true,
+      }
+    });
+  }
+
+  fn upvalue(&self, index: u64) -> Result<Handle, Error> {
+    match self.stack.get(self.frame.base) {
+      Some(Value::Closure(handle)) => self
+        .heap
+        .closure(*handle)
+        .upvalues
+        .get(index as usize)
+        .copied()
+        .ok_or(Error::InvalidUpvalue(index)),
+      _ => Err(Error::InvalidUpvalue(index)),
+    }
+  }
+
+  fn load(&mut self, constant: &Constant) -> Value {
+    match constant {
+      Constant::Int(int) => Value::Int(*int),
+      Constant::Float(float) => Value::Float(*float),
+      Constant::Bool(bool) => Value::Bool(*bool),
+      Constant::String(string) => {
+        Value::String(self.allocate(Object::String(string.clone())))
+      }
     }
   }
 
-  fn advance_u64(&mut self, chunk: &Chunk) -> Result<u64, Error> {
-    let bytes = [
-      self.advance(chunk)?,
-      self.advance(chunk)?,
-      self.advance(chunk)?,
-      self.advance(chunk)?,
-      self.advance(chunk)?,
-      self.advance(chunk)?,
-      self.advance(chunk)?,
-      self.advance(chunk)?,
-    ];
-    Ok(u64::from_le_bytes(bytes))
+  fn load_key(&mut self, key: Key) -> Value {
+    match key {
+      Key::Int(int) => Value::Int(int),
+      Key::Bool(bool) => Value::Bool(bool),
+      Key::String(string) => {
+        Value::String(self.allocate(Object::String(string)))
+      }
+    }
   }
 
-  fn advance(&mut self, chunk: &Chunk) -> Result<u8, Error> {
-    let byte = *chunk
-      .code()
-      .get(self.current)
-      .ok_or(Error::SegmentationFault(self.current))?;
-    self.current += 1;
-    Ok(byte)
+  pub fn allocate(&mut self, object: Object) -> Handle {
+    let roots = self.stack.iter().filter_map(Value::handle);
+    self
+      .heap
+      .allocate(object, roots.chain(self.open_upvalues.iter().copied()))
   }
 
   fn push(&mut self, value: Value) {
     self.stack.push(value);
   }
 
+  // Matching rather than using `ok_or` avoids building and dropping an error on
+  // every pop.
   fn pop(&mut self) -> Result<Value, Error> {
-    self.stack.pop().ok_or(Error::EmptyStack)
+    match self.stack.pop() {
+      Some(value) => Ok(value),
+      None => Err(Error::EmptyStack),
+    }
   }
 }
 
+// Names of fields, methods and natives are stored as string constants, so that
+// the instructions referring to them are resolved when they are executed.
+fn name(chunk: &Chunk, index: u64) -> Result<&str, Error> {
+  match chunk.constant(index as usize) {
+    Some(Constant::String(name)) => Ok(name),
+    _ => Err(Error::InvalidConstant(index)),
+  }
+}
+
+fn field(
+  executable: &Executable,
+  record: &Record,
+  name: &str,
+) -> Result<usize, Error> {
+  executable
+    .structure(record.structure)
+    .ok_or(Error::InvalidStruct(record.structure as u64))?
+    .field(name)
+    .ok_or(Error::MissingField)
+}
+
+fn element(list: &[Value], index: i64) -> Result<&Value, Error> {
+  usize::try_from(index)
+    .ok()
+    .and_then(|index| list.get(index))
+    .ok_or(Error::IndexOutOfBounds(index))
+}
+
+fn element_mut(list: &mut [Value], index: i64) -> Result<&mut Value, Error> {
+  usize::try_from(index)
+    .ok()
+    .and_then(move |index| list.get_mut(index))
+    .ok_or(Error::IndexOutOfBounds(index))
+}
+
 #[cfg(test)]
 mod tests {
+  use std::{cell::RefCell, collections::HashMap, io, rc::Rc};
+
   use crate::{
-    executable::{Chunk, Constant},
+    assembly,
+    executable::{Capture, Chunk, Constant, Executable, Struct},
     instruction::Instruction,
     value::Value,
   };
 
-  use super::Machine;
+  use crate::heap::Object;
+
+  use super::{Error, Machine, Status};
 
   #[test]
   fn addition() {
@@ -273,22 +1210,999 @@ mod tests {
 
     let constant = chunk.add_constant(Constant::Int(1));
     chunk.emit(Instruction::PushConstant);
-    chunk.emit_bytes(constant.to_le_bytes());
+    chunk.emit_operand(constant as u64);
 
     let constant = chunk.add_constant(Constant::Int(2));
     chunk.emit(Instruction::PushConstant);
-    chunk.emit_bytes(constant.to_le_bytes());
+    chunk.emit_operand(constant as u64);
 
     chunk.emit(Instruction::Add);
 
     let constant = chunk.add_constant(Constant::Float(3.0));
     chunk.emit(Instruction::PushConstant);
-    chunk.emit_bytes(constant.to_le_bytes());
+    chunk.emit_operand(constant as u64);
 
     chunk.emit(Instruction::Add);
 
     chunk.emit(Instruction::Return);
 
-    assert_eq!(machine.execute(&chunk).unwrap(), Value::Float(6.0));
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Float(6.0));
+  }
+
+  // Executes the instruction on the constants, which are pushed in order.
+  fn operation(
+    instruction: Instruction,
+    operands: Vec<Constant>,
+  ) -> Result<Value, Error> {
+    let mut chunk = Chunk::new();
+    for operand in operands {
+      let constant = chunk.add_constant(operand) as u64;
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant);
+    }
+    chunk.emit(instruction);
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    Machine::new()
+      .execute(&executable)
+      .map_err(|trace| trace.error)
+  }
+
+  #[test]
+  fn integer_overflow() {
+    use Constant::Int;
+
+    for (instruction, left, right) in [
+      (Instruction::Add, i64::MAX, 1),
+      (Instruction::Add, i64::MIN, -1),
+      (Instruction::Subtract, i64::MIN, 1),
+      (Instruction::Subtract, i64::MAX, -1),
+      (Instruction::Multiply, i64::MAX, 2),
+      (Instruction::Multiply, i64::MIN, -1),
+      (Instruction::Divide, i64::MIN, -1),
+    ] {
+      assert!(matches!(
+        operation(instruction, vec![Int(left), Int(right)]),
+        Err(Error::IntegerOverflow)
+      ));
+    }
+    assert!(matches!(
+      operation(Instruction::Negate, vec![Int(i64::MIN)]),
+      Err(Error::IntegerOverflow)
+    ));
+
+    assert_eq!(
+      operation(Instruction::Add, vec![Int(i64::MAX - 1), Int(1)]).unwrap(),
+      Value::Int(i64::MAX)
+    );
+    assert_eq!(
+      operation(Instruction::Subtract, vec![Int(i64::MIN + 1), Int(1)])
+        .unwrap(),
+      Value::Int(i64::MIN)
+    );
+    assert_eq!(
+      operation(Instruction::Negate, vec![Int(i64::MAX)]).unwrap(),
+      Value::Int(-i64::MAX)
+    );
+  }
+
+  #[test]
+  fn division_by_zero() {
+    use Constant::{Float, Int};
+
+    for left in [0, 1, -1, i64::MIN] {
+      assert!(matches!(
+        operation(Instruction::Divide, vec![Int(left), Int(0)]),
+        Err(Error::DivisionByZero)
+      ));
+    }
+
+    assert_eq!(
+      operation(Instruction::Divide, vec![Int(-7), Int(2)]).unwrap(),
+      Value::Int(-3)
+    );
+    assert_eq!(
+      operation(Instruction::Divide, vec![Float(1.0), Int(0)]).unwrap(),
+      Value::Float(f64::INFINITY)
+    );
+  }
+
+  #[test]
+  fn remainder() {
+    use Constant::{Float, Int};
+
+    for (left, right, result) in [(7, 3, 1), (-7, 3, -1), (7, -3, 1)] {
+      assert_eq!(
+        operation(Instruction::Remainder, vec![Int(left), Int(right)]).unwrap(),
+        Value::Int(result)
+      );
+    }
+    assert!(matches!(
+      operation(Instruction::Remainder, vec![Int(1), Int(0)]),
+      Err(Error::DivisionByZero)
+    ));
+    assert!(matches!(
+      operation(Instruction::Remainder, vec![Int(i64::MIN), Int(-1)]),
+      Err(Error::IntegerOverflow)
+    ));
+    assert!(matches!(
+      operation(Instruction::Remainder, vec![Float(7.0), Int(3)]),
+      Err(Error::InvalidType)
+    ));
+  }
+
+  #[test]
+  fn bitwise() {
+    use Constant::{Bool, Float, Int};
+
+    for (instruction, left, right, result) in [
+      (Instruction::BitAnd, 0b1100, 0b1010, 0b1000),
+      (Instruction::BitOr, 0b1100, 0b1010, 0b1110),
+      (Instruction::BitXor, 0b1100, 0b1010, 0b0110),
+      (Instruction::ShiftLeft, 1, 62, 1 << 62),
+      (Instruction::ShiftLeft, 1, 63, i64::MIN),
+      (Instruction::ShiftRight, -16, 2, -4),
+      (Instruction::ShiftRight, i64::MIN, 63, -1),
+    ] {
+      assert_eq!(
+        operation(instruction, vec![Int(left), Int(right)]).unwrap(),
+        Value::Int(result)
+      );
+    }
+    assert_eq!(
+      operation(Instruction::BitNot, vec![Int(0)]).unwrap(),
+      Value::Int(-1)
+    );
+
+    for (instruction, right) in [
+      (Instruction::ShiftLeft, 64),
+      (Instruction::ShiftLeft, -1),
+      (Instruction::ShiftRight, 64),
+      (Instruction::ShiftRight, -1),
+    ] {
+      assert!(matches!(
+        operation(instruction, vec![Int(1), Int(right)]),
+        Err(Error::IntegerOverflow)
+      ));
+    }
+
+    assert!(matches!(
+      operation(Instruction::BitAnd, vec![Bool(true), Bool(false)]),
+      Err(Error::InvalidType)
+    ));
+    assert!(matches!(
+      operation(Instruction::BitNot, vec![Float(1.0)]),
+      Err(Error::InvalidType)
+    ));
+  }
+
+  #[test]
+  fn set_local() {
+    let mut chunk = Chunk::new();
+
+    let constant = chunk.add_constant(Constant::Int(1));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+
+    let constant = chunk.add_constant(Constant::Int(2));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+    chunk.emit(Instruction::SetLocal);
+    chunk.emit_operand(1);
+    chunk.emit(Instruction::Pop);
+
+    chunk.emit(Instruction::PushLocal);
+    chunk.emit_operand(1);
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    let mut machine = Machine::new();
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(2));
+  }
+
+  #[test]
+  fn pop_n() {
+    let mut chunk = Chunk::new();
+    for int in 1..=3 {
+      let constant = chunk.add_constant(Constant::Int(int));
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant as u64);
+    }
+    chunk.emit(Instruction::PopN);
+    chunk.emit_operand(2);
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    let mut machine = Machine::new();
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(3));
+  }
+
+  #[test]
+  fn string() {
+    let mut machine = Machine::new();
+
+    let mut chunk = Chunk::new();
+
+    let constant = chunk.add_constant(Constant::String("foo".to_string()));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+
+    let constant = chunk.add_constant(Constant::String("bär".to_string()));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+
+    chunk.emit(Instruction::Add);
+    chunk.emit(Instruction::Length);
+
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(6));
+  }
+
+  #[test]
+  fn gc_stress() {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+
+    let mut chunk = Chunk::new();
+
+    let constant = chunk.add_constant(Constant::String("foo".to_string()));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+
+    for _ in 0..3 {
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant as u64);
+      chunk.emit(Instruction::Add);
+    }
+
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    let value = machine.execute(&executable).unwrap();
+    assert_eq!(value.display(machine.heap()).to_string(), "foofoofoofoo");
+
+    let stats = machine.gc_stats();
+    assert_eq!(stats.collections, stats.allocated);
+    assert_eq!(stats.live, 1);
+  }
+
+  #[test]
+  fn list() {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+
+    let mut chunk = Chunk::new();
+
+    for int in [1, 2, 3] {
+      let constant = chunk.add_constant(Constant::Int(int));
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant as u64);
+    }
+    chunk.emit(Instruction::MakeList);
+    chunk.emit_operand(3);
+
+    let constant = chunk.add_constant(Constant::Int(3));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+    chunk.emit(Instruction::Index);
+
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::IndexOutOfBounds(3))
+    ));
+  }
+
+  #[test]
+  fn map() {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+
+    let mut chunk = Chunk::new();
+    chunk.add_local(String::new());
+
+    let foo = chunk.add_constant(Constant::String("foo".to_string()));
+    let bar = chunk.add_constant(Constant::String("bar".to_string()));
+    let one = chunk.add_constant(Constant::Int(1));
+    for constant in [foo, one, bar, one] {
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant as u64);
+    }
+    chunk.emit(Instruction::MakeMap);
+    chunk.emit_operand(2);
+    let map = chunk.add_local("map".to_string()) as u64;
+
+    chunk.emit(Instruction::PushLocal);
+    chunk.emit_operand(map);
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(foo as u64);
+    chunk.emit(Instruction::Remove);
+    chunk.emit(Instruction::Pop);
+
+    chunk.emit(Instruction::PushLocal);
+    chunk.emit_operand(map);
+    chunk.emit(Instruction::Keys);
+
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    let value = machine.execute(&executable).unwrap();
+    assert_eq!(value.display(machine.heap()).to_string(), "[bar]");
+  }
+
+  #[test]
+  fn map_length() {
+    let mut machine = Machine::new();
+
+    let mut chunk = Chunk::new();
+
+    let foo = chunk.add_constant(Constant::String("foo".to_string()));
+    let bar = chunk.add_constant(Constant::String("bar".to_string()));
+    let one = chunk.add_constant(Constant::Int(1));
+    for constant in [foo, one, bar, one, foo, one] {
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant as u64);
+    }
+    chunk.emit(Instruction::MakeMap);
+    chunk.emit_operand(3);
+    chunk.emit(Instruction::Length);
+
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(2));
+  }
+
+  #[test]
+  fn unhashable_key() {
+    let mut machine = Machine::new();
+
+    let mut chunk = Chunk::new();
+
+    chunk.emit(Instruction::MakeList);
+    chunk.emit_operand(0);
+    chunk.emit(Instruction::PushUnit);
+    chunk.emit(Instruction::MakeMap);
+    chunk.emit_operand(1);
+
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::UnhashableKey)
+    ));
+  }
+
+  #[test]
+  fn call() {
+    let mut machine = Machine::new();
+
+    let mut executable = Executable::new();
+
+    let mut main = Chunk::new();
+    main.emit(Instruction::PushFunction);
+    main.emit_operand(1);
+    let constant = main.add_constant(Constant::Int(20)) as u64;
+    main.emit(Instruction::PushConstant);
+    main.emit_operand(constant);
+    let constant = main.add_constant(Constant::Int(22)) as u64;
+    main.emit(Instruction::PushConstant);
+    main.emit_operand(constant);
+    main.emit(Instruction::Call);
+    main.emit_operand(2);
+    main.emit(Instruction::Return);
+    executable.add_chunk(main);
+
+    let mut add = Chunk::new();
+    add.add_local("add".to_string());
+    let left = add.add_parameter("left".to_string()) as u64;
+    let right = add.add_parameter("right".to_string()) as u64;
+    add.emit(Instruction::PushLocal);
+    add.emit_operand(left);
+    add.emit(Instruction::PushLocal);
+    add.emit_operand(right);
+    add.emit(Instruction::Add);
+    add.emit(Instruction::Return);
+    executable.add_chunk(add);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(42));
+  }
+
+  #[test]
+  fn closure() {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+
+    let mut executable = Executable::new();
+
+    // Calls the counter returned by `make_counter` twice.
+    let mut main = Chunk::new();
+    main.add_local("main".to_string());
+    main.emit(Instruction::PushFunction);
+    main.emit_operand(1);
+    main.emit(Instruction::Call);
+    main.emit_operand(0);
+    let counter = main.add_local("counter".to_string()) as u64;
+    for _ in 0..2 {
+      main.emit(Instruction::PushLocal);
+      main.emit_operand(counter);
+      main.emit(Instruction::Call);
+      main.emit_operand(0);
+    }
+    main.emit(Instruction::Return);
+    executable.add_chunk(main);
+
+    // Binds `count` and returns a closure over it.
+    let mut make_counter = Chunk::new();
+    make_counter.add_local("make_counter".to_string());
+    let constant = make_counter.add_constant(Constant::Int(0)) as u64;
+    make_counter.emit(Instruction::PushConstant);
+    make_counter.emit_operand(constant);
+    make_counter.add_local("count".to_string());
+    make_counter.emit(Instruction::MakeClosure);
+    make_counter.emit_operand(2);
+    make_counter.emit(Instruction::Return);
+    executable.add_chunk(make_counter);
+
+    // Increments the captured `count` and returns it.
+    let mut increment = Chunk::new();
+    increment.add_local(String::new());
+    increment.add_capture(Capture {
+      is_local: true,
+      index: 1,
+    });
+    increment.emit(Instruction::GetUpvalue);
+    increment.emit_operand(0);
+    let constant = increment.add_constant(Constant::Int(1)) as u64;
+    increment.emit(Instruction::PushConstant);
+    increment.emit_operand(constant);
+    increment.emit(Instruction::Add);
+    increment.emit(Instruction::SetUpvalue);
+    increment.emit_operand(0);
+    increment.emit(Instruction::Return);
+    executable.add_chunk(increment);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(2));
+  }
+
+  #[test]
+  fn call_invalid_arity() {
+    let mut machine = Machine::new();
+
+    let mut executable = Executable::new();
+
+    let mut main = Chunk::new();
+    main.emit(Instruction::PushFunction);
+    main.emit_operand(1);
+    main.emit(Instruction::Call);
+    main.emit_operand(0);
+    main.emit(Instruction::Return);
+    executable.add_chunk(main);
+
+    let mut identity = Chunk::new();
+    identity.add_local("identity".to_string());
+    identity.add_parameter("value".to_string());
+    identity.emit(Instruction::PushLocal);
+    identity.emit_operand(1);
+    identity.emit(Instruction::Return);
+    executable.add_chunk(identity);
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::InvalidArity(1, 0))
+    ));
+  }
+
+  const FAIL: &str = r#"
+    .chunk "fail"
+      .arity 1
+      .local "fail"
+      .local "value"
+      PushLocal value
+      Throw
+  "#;
+
+  #[test]
+  fn throw() {
+    // The callee's frame is unwound along with the values main pushed within
+    // the range of the handler.
+    let source = r#"
+      .chunk "main"
+        .arity 0
+        .local "main"
+        .constant 1
+        .constant 2
+        .handler start catch catch
+      start:
+        PushConstant 0
+        PushFunction 1
+        PushConstant 1
+        Call 1
+        Add
+        Return
+      catch:
+        Return
+    "#;
+    let executable = assembly::assemble(&(source.to_string() + FAIL)).unwrap();
+
+    let mut machine = Machine::new();
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(2));
+    assert_eq!(machine.stack().len(), 0);
+  }
+
+  #[test]
+  fn throw_uncaught() {
+    let source = r#"
+      .chunk "main"
+        .arity 0
+        .local "main"
+        .constant true
+        PushFunction 1
+        PushConstant 0
+        Call 1
+        Return
+    "#;
+    let executable = assembly::assemble(&(source.to_string() + FAIL)).unwrap();
+
+    let trace = Machine::new().execute(&executable).unwrap_err();
+    assert!(matches!(trace.error, Error::Uncaught(Value::Bool(true))));
+    let frames: Vec<&str> = trace
+      .frames
+      .iter()
+      .map(|frame| frame.name.as_str())
+      .collect();
+    assert_eq!(frames, vec!["fail", "main"]);
+  }
+
+  #[test]
+  fn record() {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+
+    let mut executable = Executable::new();
+    executable.add_struct(Struct {
+      name: "Point".to_string(),
+      fields: vec!["x".to_string(), "y".to_string()],
+      methods: HashMap::new(),
+    });
+
+    let mut chunk = Chunk::new();
+    for int in [1, 2] {
+      let constant = chunk.add_constant(Constant::Int(int)) as u64;
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant);
+    }
+    chunk.emit(Instruction::MakeRecord);
+    chunk.emit_operand(0);
+
+    let y = chunk.add_constant(Constant::String("y".to_string())) as u64;
+    chunk.emit(Instruction::PushLocal);
+    chunk.emit_operand(1);
+    let constant = chunk.add_constant(Constant::Int(3)) as u64;
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant);
+    chunk.emit(Instruction::SetField);
+    chunk.emit_operand(y);
+    chunk.emit(Instruction::Pop);
+
+    chunk.emit(Instruction::GetField);
+    chunk.emit_operand(y);
+    chunk.emit(Instruction::Return);
+    executable.add_chunk(chunk);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(3));
+  }
+
+  #[test]
+  fn record_missing_field() {
+    let mut machine = Machine::new();
+
+    let mut executable = Executable::new();
+    executable.add_struct(Struct {
+      name: "Empty".to_string(),
+      fields: Vec::new(),
+      methods: HashMap::new(),
+    });
+
+    let mut chunk = Chunk::new();
+    let z = chunk.add_constant(Constant::String("z".to_string())) as u64;
+    chunk.emit(Instruction::MakeRecord);
+    chunk.emit_operand(0);
+    chunk.emit(Instruction::GetField);
+    chunk.emit_operand(z);
+    chunk.emit(Instruction::Return);
+    executable.add_chunk(chunk);
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::MissingField)
+    ));
+  }
+
+  #[test]
+  fn invoke() {
+    let mut machine = Machine::new();
+
+    let mut executable = Executable::new();
+    let mut methods = HashMap::new();
+    methods.insert("sum".to_string(), 1);
+    executable.add_struct(Struct {
+      name: "Pair".to_string(),
+      fields: vec!["left".to_string(), "right".to_string()],
+      methods,
+    });
+
+    let mut main = Chunk::new();
+    for int in [1, 2] {
+      let constant = main.add_constant(Constant::Int(int)) as u64;
+      main.emit(Instruction::PushConstant);
+      main.emit_operand(constant);
+    }
+    main.emit(Instruction::MakeRecord);
+    main.emit_operand(0);
+    let constant = main.add_constant(Constant::Int(3)) as u64;
+    main.emit(Instruction::PushConstant);
+    main.emit_operand(constant);
+    let sum = main.add_constant(Constant::String("sum".to_string())) as u64;
+    main.emit(Instruction::Invoke);
+    main.emit_operand(sum);
+    main.emit_operand(1);
+    main.emit(Instruction::Return);
+    executable.add_chunk(main);
+
+    let mut sum = Chunk::new();
+    sum.add_local("self".to_string());
+    sum.add_parameter("extra".to_string());
+    for field in ["left", "right"] {
+      let field = sum.add_constant(Constant::String(field.to_string())) as u64;
+      sum.emit(Instruction::PushLocal);
+      sum.emit_operand(0);
+      sum.emit(Instruction::GetField);
+      sum.emit_operand(field);
+    }
+    sum.emit(Instruction::Add);
+    sum.emit(Instruction::PushLocal);
+    sum.emit_operand(1);
+    sum.emit(Instruction::Add);
+    sum.emit(Instruction::Return);
+    executable.add_chunk(sum);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(6));
+  }
+
+  #[test]
+  fn call_native() {
+    let mut machine = Machine::new();
+    machine.set_gc_stress(true);
+    machine.register_native("string::repeat", 2, |machine, arguments| {
+      match (&arguments[0], &arguments[1]) {
+        (Value::String(string), Value::Int(count)) => {
+          let string = machine.heap().string(*string).repeat(*count as usize);
+          Ok(Value::String(machine.allocate(Object::String(string))))
+        }
+        _ => Err(Error::Native("expected a string and an int".to_string())),
+      }
+    });
+
+    let mut chunk = Chunk::new();
+    let constant = chunk.add_constant(Constant::String("ab".to_string()));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+    let constant = chunk.add_constant(Constant::Int(3));
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant as u64);
+    let repeat =
+      chunk.add_constant(Constant::String("string::repeat".to_string()));
+    chunk.emit(Instruction::CallNative);
+    chunk.emit_operand(repeat as u64);
+    chunk.emit_operand(2);
+    chunk.emit(Instruction::Length);
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Int(6));
+  }
+
+  #[test]
+  fn call_native_undefined() {
+    let mut machine = Machine::new();
+
+    let mut chunk = Chunk::new();
+    let name = chunk.add_constant(Constant::String("missing".to_string()));
+    chunk.emit(Instruction::CallNative);
+    chunk.emit_operand(name as u64);
+    chunk.emit_operand(0);
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::InvalidNative(name)) if name == "missing"
+    ));
+  }
+
+  #[derive(Clone)]
+  struct Output(Rc<RefCell<Vec<u8>>>);
+
+  impl io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn print() {
+    let output = Output(Rc::new(RefCell::new(Vec::new())));
+    let mut machine = Machine::new();
+    machine.set_output(output.clone());
+
+    let mut chunk = Chunk::new();
+    for constant in [Constant::Int(1), Constant::String("foo".to_string())] {
+      let constant = chunk.add_constant(constant) as u64;
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant);
+      chunk.emit(Instruction::MakeList);
+      chunk.emit_operand(1);
+      chunk.emit(Instruction::Print);
+      chunk.emit(Instruction::Pop);
+    }
+    let constant = chunk.add_constant(Constant::Int(2)) as u64;
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant);
+    chunk.emit(Instruction::Pop);
+    chunk.emit(Instruction::PushUnit);
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert_eq!(machine.execute(&executable).unwrap(), Value::Unit);
+    assert_eq!(output.0.borrow().as_slice(), b"[1]\n[foo]\n");
+  }
+
+  #[test]
+  fn step() {
+    let mut machine = Machine::new();
+
+    let mut executable = Executable::new();
+
+    let mut main = Chunk::new();
+    main.emit(Instruction::PushFunction);
+    main.emit_operand(1);
+    main.emit(Instruction::Call);
+    main.emit_operand(0);
+    main.emit(Instruction::Return);
+    executable.add_chunk(main);
+
+    let mut one = Chunk::new();
+    let constant = one.add_constant(Constant::Int(1)) as u64;
+    one.emit(Instruction::PushConstant);
+    one.emit_operand(constant);
+    one.emit(Instruction::Return);
+    executable.add_chunk(one);
+
+    machine.add_breakpoint(1, 2);
+    machine.start(&executable).unwrap();
+
+    assert_eq!(machine.step(&executable).unwrap(), Status::Running);
+    assert_eq!(machine.stack(), &[Value::Function(0), Value::Function(1)]);
+
+    assert_eq!(machine.resume(&executable).unwrap(), Status::Breakpoint);
+    let frames: Vec<(usize, usize)> = machine
+      .frames()
+      .map(|frame| (frame.chunk(), frame.offset()))
+      .collect();
+    assert_eq!(frames, vec![(0, 4), (1, 2)]);
+    assert_eq!(machine.stack().last(), Some(&Value::Int(1)));
+
+    assert_eq!(
+      machine.resume(&executable).unwrap(),
+      Status::Finished(Value::Int(1))
+    );
+  }
+
+  #[test]
+  fn fuel() {
+    let mut machine = Machine::new();
+    machine.set_fuel(Some(3));
+
+    let mut chunk = Chunk::new();
+    for int in [1, 2] {
+      let constant = chunk.add_constant(Constant::Int(int)) as u64;
+      chunk.emit(Instruction::PushConstant);
+      chunk.emit_operand(constant);
+    }
+    chunk.emit(Instruction::Add);
+    chunk.emit(Instruction::Return);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::OutOfFuel)
+    ));
+    assert_eq!(machine.fuel(), Some(0));
+    assert_eq!(machine.stack().last(), Some(&Value::Int(3)));
+
+    machine.add_fuel(1);
+    assert_eq!(
+      machine.resume(&executable).unwrap(),
+      Status::Finished(Value::Int(3))
+    );
+  }
+
+  #[test]
+  fn fuel_infinite_loop() {
+    let mut machine = Machine::new();
+    machine.set_fuel(Some(1000));
+
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::Jump);
+    chunk.emit_operand(0);
+
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::OutOfFuel)
+    ));
+  }
+
+  // Returns an executable whose entry function calls a function that calls
+  // itself forever with a list holding its argument.
+  fn infinite_recursion() -> Executable {
+    let mut executable = Executable::new();
+
+    let mut main = Chunk::new();
+    main.emit(Instruction::PushFunction);
+    main.emit_operand(1);
+    main.emit(Instruction::PushUnit);
+    main.emit(Instruction::Call);
+    main.emit_operand(1);
+    main.emit(Instruction::Return);
+    executable.add_chunk(main);
+
+    let mut recurse = Chunk::new();
+    recurse.add_local("recurse".to_string());
+    recurse.add_parameter("value".to_string());
+    recurse.emit(Instruction::PushFunction);
+    recurse.emit_operand(1);
+    recurse.emit(Instruction::PushLocal);
+    recurse.emit_operand(1);
+    recurse.emit(Instruction::MakeList);
+    recurse.emit_operand(1);
+    recurse.emit(Instruction::Call);
+    recurse.emit_operand(1);
+    recurse.emit(Instruction::Return);
+    executable.add_chunk(recurse);
+
+    executable
+  }
+
+  #[test]
+  fn max_frames() {
+    let mut machine = Machine::new();
+    machine.set_max_frames(Some(10));
+
+    assert!(matches!(
+      machine
+        .execute(&infinite_recursion())
+        .map_err(|trace| trace.error),
+      Err(Error::FrameOverflow)
+    ));
+    assert_eq!(machine.frames().count(), 10);
+  }
+
+  #[test]
+  fn max_stack() {
+    let mut machine = Machine::new();
+    machine.set_max_stack(Some(100));
+
+    assert!(matches!(
+      machine
+        .execute(&infinite_recursion())
+        .map_err(|trace| trace.error),
+      Err(Error::StackOverflow)
+    ));
+    assert!(machine.stack().len() <= 101);
+  }
+
+  #[test]
+  fn max_heap() {
+    let mut machine = Machine::new();
+    machine.set_max_heap(Some(4096));
+
+    assert!(matches!(
+      machine
+        .execute(&infinite_recursion())
+        .map_err(|trace| trace.error),
+      Err(Error::OutOfMemory)
+    ));
+    assert!(machine.gc_stats().collections > 0);
+  }
+
+  #[test]
+  fn max_heap_growth() {
+    // The map is allocated once and then grows without bound, without any
+    // further allocation.
+    let source = r#"
+      .chunk "main"
+        .arity 0
+        .local "main"
+        .local "map"
+        .local "count"
+        .constant 0
+        .constant 1
+        MakeMap 0
+        PushConstant 0
+      loop:
+        PushLocal map
+        PushLocal count
+        PushLocal count
+        SetIndex
+        Pop
+        PushLocal count
+        PushConstant 1
+        Add
+        SetLocal count
+        Pop
+        Jump loop
+    "#;
+    let executable = assembly::assemble(source).unwrap();
+
+    let mut machine = Machine::new();
+    machine.set_max_heap(Some(4096));
+
+    assert!(matches!(
+      machine.execute(&executable).map_err(|trace| trace.error),
+      Err(Error::OutOfMemory)
+    ));
+    assert_eq!(machine.gc_stats().allocated, 1);
+  }
+
+  #[test]
+  fn empty() {
+    let mut machine = Machine::new();
+    assert!(matches!(
+      machine.start(&Executable::new()),
+      Err(Error::InvalidFunction(0))
+    ));
   }
 }
@@ -1,6 +1,6 @@
 use std::fmt;
 
-use crate::executable::Constant;
+use crate::heap::{Handle, Heap};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
@@ -8,25 +8,102 @@ pub enum Value {
   Int(i64),
   Float(f64),
   Bool(bool),
+  String(Handle),
+  List(Handle),
+  Map(Handle),
+  Record(Handle),
+  Function(usize),
+  Closure(Handle),
 }
 
-impl From<Constant> for Value {
-  fn from(constant: Constant) -> Self {
-    match constant {
-      Constant::Int(int) => Value::Int(int),
-      Constant::Float(float) => Value::Float(float),
-      Constant::Bool(bool) => Value::Bool(bool),
+impl Value {
+  pub fn handle(&self) -> Option<Handle> {
+    match self {
+      Value::String(handle)
+      | Value::List(handle)
+      | Value::Map(handle)
+      | Value::Record(handle)
+      | Value::Closure(handle) => Some(*handle),
+      _ => None,
+    }
+  }
+
+  // Converts the value into a map key, if it is of a hashable type.
+  pub fn key(&self, heap: &Heap) -> Option<Key> {
+    match self {
+      Value::Int(int) => Some(Key::Int(*int)),
+      Value::Bool(bool) => Some(Key::Bool(*bool)),
+      Value::String(handle) => Some(Key::String(heap.string(*handle).into())),
+      _ => None,
     }
   }
+
+  pub fn display<'a>(&'a self, heap: &'a Heap) -> Display<'a> {
+    Display { value: self, heap }
+  }
 }
 
-impl fmt::Display for Value {
+pub struct Display<'a> {
+  value: &'a Value,
+  heap: &'a Heap,
+}
+
+impl<'a> fmt::Display for Display<'a> {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    match self {
+    match self.value {
       Value::Unit => write!(f, "()"),
       Value::Int(int) => write!(f, "{}", int),
       Value::Float(float) => write!(f, "{}", float),
       Value::Bool(bool) => write!(f, "{}", bool),
+      Value::String(handle) => write!(f, "{}", self.heap.string(*handle)),
+      Value::List(handle) => {
+        write!(f, "[")?;
+        for (index, element) in self.heap.list(*handle).iter().enumerate() {
+          if index > 0 {
+            write!(f, ", ")?;
+          }
+          write!(f, "{}", element.display(self.heap))?;
+        }
+        write!(f, "]")
+      }
+      Value::Map(handle) => {
+        let map = self.heap.map(*handle);
+        if map.is_empty() {
+          return write!(f, "[:]");
+        }
+        write!(f, "[")?;
+        for (index, (key, value)) in map.iter().enumerate() {
+          if index > 0 {
+            write!(f, ", ")?;
+          }
+          write!(f, "{}: {}", key, value.display(self.heap))?;
+        }
+        write!(f, "]")
+      }
+      Value::Record(handle) => {
+        write!(f, "<record {:#010x}>", self.heap.record(*handle).structure)
+      }
+      Value::Function(index) => write!(f, "<fn {:#010x}>", index),
+      Value::Closure(handle) => {
+        write!(f, "<fn {:#010x}>", self.heap.closure(*handle).function)
+      }
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Key {
+  Int(i64),
+  Bool(bool),
+  String(String),
+}
+
+impl fmt::Display for Key {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Key::Int(int) => write!(f, "{}", int),
+      Key::Bool(bool) => write!(f, "{}", bool),
+      Key::String(string) => write!(f, "{}", string),
     }
   }
 }
@@ -0,0 +1,490 @@
+use std::collections::BTreeMap;
+
+use num_traits::FromPrimitive;
+
+use crate::{
+  executable::{Chunk, Constant, Executable},
+  instruction::{self, Instruction},
+};
+
+// The first field of each variant is the offset of the offending instruction.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+  InvalidInstruction(usize, u8),
+  MissingOperand(usize),
+  InvalidJump(usize, u64),
+  InvalidConstant(usize, u64),
+  InvalidLocal(usize, u64),
+  InvalidFunction(usize, u64),
+  InvalidUpvalue(usize, u64),
+  InvalidStruct(usize, u64),
+  StackUnderflow(usize),
+  // The depth the stack was first found with, and the depth it was found with
+  // along another path.
+  InconsistentStack(usize, usize, usize),
+  MissingReturn(usize),
+  InvalidHandler(usize),
+}
+
+// Verifies every chunk of the executable, returning the index of the chunk that
+// failed verification along with the error.
+pub fn verify(executable: &Executable) -> Result<(), (usize, Error)> {
+  for index in 0..executable.chunks().len() {
+    verify_chunk(executable, index).map_err(|error| (index, error))?;
+  }
+  Ok(())
+}
+
+pub fn verify_chunk(
+  executable: &Executable,
+  index: usize,
+) -> Result<(), Error> {
+  analyze(executable, index).map(|_| ())
+}
+
+// The instructions of a verified chunk by their offset, and the depth of the
+// stack that each of its handlers unwinds to, which is None for handlers that
+// are never entered.
+pub(crate) struct Analysis {
+  pub instructions: BTreeMap<usize, Decoded>,
+  pub handlers: Vec<Option<usize>>,
+}
+
+pub(crate) fn analyze(
+  executable: &Executable,
+  index: usize,
+) -> Result<Analysis, Error> {
+  let chunk = match executable.chunk(index) {
+    Some(chunk) => chunk,
+    None => return Err(Error::InvalidFunction(0, index as u64)),
+  };
+
+  let instructions = decode(executable, chunk)?;
+  for (&offset, decoded) in instructions.iter() {
+    let operands = decoded.operands;
+    if let Instruction::Jump | Instruction::JumpIf = decoded.instruction {
+      if !instructions.contains_key(&(operands[0] as usize)) {
+        return Err(Error::InvalidJump(offset, operands[0]));
+      }
+    }
+  }
+
+  let handlers = chunk.handlers();
+  for handler in handlers {
+    if handler.start > handler.end
+      || handler.end > chunk.code().len()
+      || !instructions.contains_key(&handler.start)
+      || !instructions.contains_key(&handler.target)
+    {
+      return Err(Error::InvalidHandler(handler.start));
+    }
+  }
+
+  // Slot 0 holds the callee, followed by the arguments.
+  let mut depths = BTreeMap::new();
+  let mut unwinds = vec![None; handlers.len()];
+  let mut pending = vec![(0, chunk.arity() + 1)];
+  loop {
+    while let Some((offset, depth)) = pending.pop() {
+      if let Some(&expected) = depths.get(&offset) {
+        if expected != depth {
+          return Err(Error::InconsistentStack(offset, expected, depth));
+        }
+        continue;
+      }
+      depths.insert(offset, depth);
+
+      let Decoded {
+        instruction,
+        operands,
+        next,
+      } = match instructions.get(&offset) {
+        Some(&decoded) => decoded,
+        None => return Err(Error::MissingReturn(offset)),
+      };
+
+      match instruction {
+        Instruction::PushLocal | Instruction::SetLocal
+          if operands[0] as usize >= depth =>
+        {
+          return Err(Error::InvalidLocal(offset, operands[0]));
+        }
+        Instruction::MakeClosure => {
+          let function = executable
+            .chunk(operands[0] as usize)
+            .ok_or(Error::InvalidFunction(offset, operands[0]))?;
+          for capture in function.captures() {
+            let is_valid = if capture.is_local {
+              capture.index < depth
+            } else {
+              capture.index < chunk.captures().len()
+            };
+            if !is_valid {
+              return Err(Error::InvalidUpvalue(offset, capture.index as u64));
+            }
+          }
+        }
+        _ => {}
+      }
+
+      let (pops, pushes) = effect(executable, instruction, &operands)
+        .ok_or(Error::StackUnderflow(offset))?;
+      if pops > depth {
+        return Err(Error::StackUnderflow(offset));
+      }
+      let depth = depth - pops + pushes;
+
+      match instruction {
+        Instruction::Return | Instruction::Throw => {}
+        Instruction::Jump => pending.push((operands[0] as usize, depth)),
+        Instruction::JumpIf => {
+          pending.push((operands[0] as usize, depth));
+          pending.push((next, depth));
+        }
+        _ if next >= chunk.code().len() => {
+          return Err(Error::MissingReturn(offset));
+        }
+        _ => pending.push((next, depth)),
+      }
+    }
+
+    // A handler is entered once an instruction within its range is reached,
+    // with the stack at the depth it had at the start of the range and the
+    // thrown value on top.
+    let mut is_done = true;
+    for (handler, unwind) in handlers.iter().zip(unwinds.iter_mut()) {
+      if unwind.is_some()
+        || depths.range(handler.start..handler.end).next().is_none()
+      {
+        continue;
+      }
+      let depth = *depths
+        .get(&handler.start)
+        .ok_or(Error::InvalidHandler(handler.start))?;
+      *unwind = Some(depth);
+      pending.push((handler.target, depth + 1));
+      is_done = false;
+    }
+    if is_done {
+      break;
+    }
+  }
+
+  // Unwinding only ever shrinks the stack, so no instruction within the range
+  // of a handler may pop values from beneath the depth it unwinds to.
+  for (handler, unwind) in handlers.iter().zip(unwinds.iter()) {
+    let unwind = match unwind {
+      Some(unwind) => *unwind,
+      None => continue,
+    };
+    for (&offset, &depth) in depths.range(handler.start..handler.end) {
+      let decoded = instructions[&offset];
+      let (pops, _) =
+        effect(executable, decoded.instruction, &decoded.operands)
+          .ok_or(Error::StackUnderflow(offset))?;
+      if depth - pops < unwind {
+        return Err(Error::StackUnderflow(offset));
+      }
+    }
+  }
+
+  Ok(Analysis {
+    instructions,
+    handlers: unwinds,
+  })
+}
+
+// An instruction along with its operands, of which only as many as the
+// instruction takes are used, and the offset of the instruction after it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Decoded {
+  pub instruction: Instruction,
+  pub operands: [u64; 2],
+  pub next: usize,
+}
+
+// Decodes every instruction of the chunk by its offset, checking the operands
+// that do not depend on the state of the stack.
+pub(crate) fn decode(
+  executable: &Executable,
+  chunk: &Chunk,
+) -> Result<BTreeMap<usize, Decoded>, Error> {
+  let code = chunk.code();
+  let mut instructions = BTreeMap::new();
+
+  let mut offset = 0;
+  while offset < code.len() {
+    let instruction = Instruction::from_u8(code[offset])
+      .ok_or(Error::InvalidInstruction(offset, code[offset]))?;
+
+    let mut operands = [0; 2];
+    let mut next = offset + 1;
+    for operand in operands.iter_mut().take(instruction.operands()) {
+      let (value, len) = instruction::decode_operand(&code[next..])
+        .ok_or(Error::MissingOperand(offset))?;
+      *operand = value;
+      next += len;
+    }
+
+    match instruction {
+      Instruction::PushConstant
+        if chunk.constant(operands[0] as usize).is_none() =>
+      {
+        return Err(Error::InvalidConstant(offset, operands[0]));
+      }
+      Instruction::GetField
+      | Instruction::SetField
+      | Instruction::CallNative
+      | Instruction::Invoke
+        if !matches!(
+          chunk.constant(operands[0] as usize),
+          Some(Constant::String(_))
+        ) =>
+      {
+        return Err(Error::InvalidConstant(offset, operands[0]));
+      }
+      Instruction::PushFunction | Instruction::MakeClosure
+        if executable.chunk(operands[0] as usize).is_none() =>
+      {
+        return Err(Error::InvalidFunction(offset, operands[0]));
+      }
+      Instruction::GetUpvalue | Instruction::SetUpvalue
+        if operands[0] as usize >= chunk.captures().len() =>
+      {
+        return Err(Error::InvalidUpvalue(offset, operands[0]));
+      }
+      Instruction::MakeRecord
+        if executable.structure(operands[0] as usize).is_none() =>
+      {
+        return Err(Error::InvalidStruct(offset, operands[0]));
+      }
+      _ => {}
+    }
+
+    let decoded = Decoded {
+      instruction,
+      operands,
+      next,
+    };
+    instructions.insert(offset, decoded);
+    offset = next;
+  }
+
+  Ok(instructions)
+}
+
+// Returns the number of values the instruction pops off the stack, and the
+// number of values it pushes onto the stack afterwards. Counts too large to
+// represent give None, since no stack could hold that many values.
+fn effect(
+  executable: &Executable,
+  instruction: Instruction,
+  operands: &[u64],
+) -> Option<(usize, usize)> {
+  let effect = match instruction {
+    Instruction::PushConstant
+    | Instruction::PushLocal
+    | Instruction::PushFunction
+    | Instruction::PushUnit
+    | Instruction::MakeClosure
+    | Instruction::GetUpvalue => (0, 1),
+    Instruction::SetLocal
+    | Instruction::SetUpvalue
+    | Instruction::Keys
+    | Instruction::GetField
+    | Instruction::Print
+    | Instruction::Negate
+    | Instruction::BitNot
+    | Instruction::Not
+    | Instruction::Length => (1, 1),
+    Instruction::MakeList => (operands[0] as usize, 1),
+    Instruction::MakeMap => ((operands[0] as usize).checked_mul(2)?, 1),
+    Instruction::MakeRecord => {
+      let fields = executable
+        .structure(operands[0] as usize)
+        .map(|structure| structure.fields.len())
+        .unwrap_or(0);
+      (fields, 1)
+    }
+    Instruction::Index
+    | Instruction::Remove
+    | Instruction::SetField
+    | Instruction::Add
+    | Instruction::Subtract
+    | Instruction::Multiply
+    | Instruction::Divide
+    | Instruction::Remainder
+    | Instruction::BitAnd
+    | Instruction::BitOr
+    | Instruction::BitXor
+    | Instruction::ShiftLeft
+    | Instruction::ShiftRight
+    | Instruction::Greater
+    | Instruction::GreaterEqual
+    | Instruction::Less
+    | Instruction::LessEqual
+    | Instruction::Equal
+    | Instruction::NotEqual => (2, 1),
+    Instruction::SetIndex => (3, 1),
+    Instruction::Pop | Instruction::JumpIf => (1, 0),
+    Instruction::PopN => ((operands[0] as usize).checked_add(1)?, 1),
+    Instruction::Jump => (0, 0),
+    Instruction::Call => ((operands[0] as usize).checked_add(1)?, 1),
+    Instruction::CallNative => (operands[1] as usize, 1),
+    Instruction::Invoke => ((operands[1] as usize).checked_add(1)?, 1),
+    Instruction::Return | Instruction::Throw => (1, 0),
+  };
+  Some(effect)
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{
+    executable::{Chunk, Constant, Executable, Handler},
+    instruction::Instruction,
+  };
+
+  use super::{verify, Error};
+
+  fn executable(chunk: Chunk) -> Executable {
+    let mut executable = Executable::new();
+    executable.add_chunk(chunk);
+    executable
+  }
+
+  #[test]
+  fn valid() {
+    let mut chunk = Chunk::new();
+    let constant = chunk.add_constant(Constant::Bool(true)) as u64;
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(constant);
+    let jump = chunk.emit_jump(Instruction::JumpIf);
+    chunk.emit(Instruction::PushUnit);
+    chunk.emit(Instruction::Return);
+    chunk.patch_jump(jump, chunk.code().len());
+    chunk.emit(Instruction::PushLocal);
+    chunk.emit_operand(0);
+    chunk.emit(Instruction::Return);
+
+    assert_eq!(verify(&executable(chunk)), Ok(()));
+  }
+
+  #[test]
+  fn invalid_jump() {
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(0);
+    chunk.emit(Instruction::Jump);
+    chunk.emit_operand(1);
+    chunk.add_constant(Constant::Int(0));
+
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::InvalidJump(2, 1)))
+    );
+  }
+
+  #[test]
+  fn invalid_index() {
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushConstant);
+    chunk.emit_operand(0);
+    chunk.emit(Instruction::Return);
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::InvalidConstant(0, 0)))
+    );
+
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushLocal);
+    chunk.emit_operand(1);
+    chunk.emit(Instruction::Return);
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::InvalidLocal(0, 1)))
+    );
+  }
+
+  #[test]
+  fn stack() {
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::Add);
+    chunk.emit(Instruction::Return);
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::StackUnderflow(0)))
+    );
+
+    // Only one of the branches pushes a value before they join.
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushLocal);
+    chunk.emit_operand(0);
+    chunk.emit(Instruction::JumpIf);
+    chunk.emit_operand(5);
+    chunk.emit(Instruction::PushUnit);
+    chunk.emit(Instruction::Return);
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::InconsistentStack(5, 2, 1)))
+    );
+
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushUnit);
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::MissingReturn(0)))
+    );
+
+    // Counts too large to pop are rejected rather than overflowing.
+    for (instruction, operand) in [
+      (Instruction::PopN, u64::MAX),
+      (Instruction::MakeMap, 1 << 63),
+      (Instruction::Call, u64::MAX),
+    ] {
+      let mut chunk = Chunk::new();
+      chunk.emit(instruction);
+      chunk.emit_operand(operand);
+      chunk.emit(Instruction::Return);
+      assert_eq!(
+        verify(&executable(chunk)),
+        Err((0, Error::StackUnderflow(0)))
+      );
+    }
+  }
+
+  #[test]
+  fn handler() {
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushUnit);
+    chunk.emit(Instruction::Return);
+    chunk.add_handler(Handler {
+      start: 0,
+      end: 1,
+      target: 2,
+    });
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::InvalidHandler(0)))
+    );
+
+    // The range starts with the callee above the unwound depth, so popping it
+    // would leave unwinding to grow the stack.
+    let mut chunk = Chunk::new();
+    chunk.emit(Instruction::PushUnit);
+    chunk.emit(Instruction::PushUnit);
+    chunk.emit(Instruction::Pop);
+    chunk.emit(Instruction::Pop);
+    chunk.emit(Instruction::Return);
+    chunk.emit(Instruction::Pop);
+    chunk.emit(Instruction::Return);
+    chunk.add_handler(Handler {
+      start: 1,
+      end: 4,
+      target: 5,
+    });
+    assert_eq!(
+      verify(&executable(chunk)),
+      Err((0, Error::StackUnderflow(3)))
+    );
+  }
+}